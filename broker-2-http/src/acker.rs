@@ -0,0 +1,154 @@
+/// Acknowledging every message individually costs a round trip to the broker per message.
+/// Instead, we collect the delivery tags of messages we're done with and acknowledge them together,
+/// using "multiple=true" to acknowledge everything up to a delivery tag at once.
+///
+/// Delivery tags are only meaningful on the channel which received them, so use a new AckBatcher for each channel.
+use amqprs::channel::{BasicAckArguments, BasicNackArguments, Channel};
+use std::{collections::BTreeSet, future::Future, time::Duration};
+
+use crate::metrics::{ACK_BATCHES, MESSAGES_ACKED};
+
+/// Answers the broker about messages received on a channel. Implemented by "Channel", and by test doubles.
+pub trait Acknowledge {
+    fn send_ack(
+        &self,
+        delivery_tag: u64,
+        multiple: bool,
+    ) -> impl Future<Output = Result<(), amqprs::error::Error>> + Send;
+
+    fn send_nack(
+        &self,
+        delivery_tag: u64,
+        requeue: bool,
+    ) -> impl Future<Output = Result<(), amqprs::error::Error>> + Send;
+}
+
+impl Acknowledge for Channel {
+    async fn send_ack(
+        &self,
+        delivery_tag: u64,
+        multiple: bool,
+    ) -> Result<(), amqprs::error::Error> {
+        self.basic_ack(BasicAckArguments::new(delivery_tag, multiple))
+            .await
+    }
+
+    async fn send_nack(
+        &self,
+        delivery_tag: u64,
+        requeue: bool,
+    ) -> Result<(), amqprs::error::Error> {
+        self.basic_nack(BasicNackArguments::new(delivery_tag, false, requeue))
+            .await
+    }
+}
+
+pub struct AckBatcher {
+    /// messages we're done with, which have not been acknowledged yet
+    ready: BTreeSet<u64>,
+    /// messages which must NOT be acknowledged by a "multiple" ack (i.e. because nobody received them)
+    held: BTreeSet<u64>,
+    batch_size: usize,
+    interval: Duration,
+}
+
+impl AckBatcher {
+    /// acknowledgements are sent once "batch_size" messages are ready, or when "flush" is called (at least every "interval")
+    pub fn new(batch_size: usize, interval: Duration) -> Self {
+        Self {
+            ready: BTreeSet::new(),
+            held: BTreeSet::new(),
+            batch_size: batch_size.max(1),
+            interval,
+        }
+    }
+
+    /// how often "flush" should be called, so that messages are not left unacknowledged for long
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Mark a message as done, acknowledging it (and any other ready messages) if the batch is full.
+    pub async fn ack(&mut self, channel: &impl Acknowledge, delivery_tag: u64) {
+        self.ready.insert(delivery_tag);
+        if self.ready.len() >= self.batch_size {
+            self.flush(channel).await;
+        }
+    }
+
+    /// Make sure this message is never acknowledged by the batcher. It stays unacknowledged on the broker.
+    pub fn hold(&mut self, delivery_tag: u64) {
+        self.held.insert(delivery_tag);
+    }
+
+    /// A held message has been dealt with some other way (i.e. it was rejected), so it no longer blocks "multiple" acks.
+    pub fn release(&mut self, delivery_tag: u64) {
+        self.held.remove(&delivery_tag);
+    }
+
+    /// acknowledge every ready message
+    pub async fn flush(&mut self, channel: &impl Acknowledge) {
+        let count = self.ready.len();
+        if count == 0 {
+            return;
+        }
+        for (delivery_tag, multiple) in self.take_acks() {
+            tracing::debug!(
+                "ack to delivery tag {} (multiple: {})",
+                delivery_tag,
+                multiple
+            );
+            if let Err(e) = channel.send_ack(delivery_tag, multiple).await {
+                // the channel is most likely gone, and the broker will redeliver these messages
+                tracing::error!(error = ?e, "manual ack did not work");
+                return;
+            }
+            ACK_BATCHES.inc();
+        }
+        MESSAGES_ACKED.inc_by(count as u64);
+    }
+
+    /// Work out the acks to send for all ready messages, as (delivery_tag, multiple) pairs.
+    /// Messages below the lowest held message are covered by one "multiple" ack, the rest are acknowledged one by one.
+    fn take_acks(&mut self) -> Vec<(u64, bool)> {
+        let ready = std::mem::take(&mut self.ready);
+        let first_held = self.held.first().copied().unwrap_or(u64::MAX);
+        let mut acks = vec![];
+        // messages are handled in delivery order, so every unacknowledged message delivered before this one is either ready or held
+        if let Some(&below_held) = ready.range(..first_held).next_back() {
+            acks.push((below_held, true));
+        }
+        acks.extend(ready.range(first_held..).map(|tag| (*tag, false)));
+        acks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batcher() -> AckBatcher {
+        AckBatcher::new(10, Duration::from_millis(100))
+    }
+
+    #[test]
+    fn ready_messages_are_acked_together() {
+        let mut acker = batcher();
+        acker.ready.extend([1, 2, 3]);
+        assert_eq!(acker.take_acks(), vec![(3, true)]);
+        assert!(acker.take_acks().is_empty());
+    }
+
+    #[test]
+    fn held_messages_are_never_acked() {
+        let mut acker = batcher();
+        acker.ready.extend([1, 2, 4, 5]);
+        acker.hold(3);
+        assert_eq!(acker.take_acks(), vec![(2, true), (4, false), (5, false)]);
+
+        // once the held message is dealt with, "multiple" can be used again
+        acker.release(3);
+        acker.ready.extend([6, 7]);
+        assert_eq!(acker.take_acks(), vec![(7, true)]);
+    }
+}
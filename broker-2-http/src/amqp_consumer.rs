@@ -1,185 +1,933 @@
 use amqprs::{
     channel::{
-        BasicAckArguments, BasicCancelArguments, BasicConsumeArguments, Channel, ConsumerMessage,
-        QueueBindArguments, QueueDeclareArguments,
+        BasicCancelArguments, BasicConsumeArguments, BasicQosArguments, Channel, ConsumerMessage,
+        QueueBindArguments, QueueDeclareArguments, QueueUnbindArguments,
     },
     connection::Connection,
+    BasicProperties, Deliver, FieldTable, FieldValue,
 };
-use std::sync::Arc;
+use anyhow::Context;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    watch,
+};
+use tracing::Instrument;
 use uuid::Uuid;
 
-use crate::broadcaster::Broadcaster;
+use crate::acker::{AckBatcher, Acknowledge};
+use crate::broadcaster::{Broadcaster, ConfirmTarget, Confirmed};
+use crate::configuration::{ConsumerSettings, NoSubscriberSettings, QueueSettings, Settings};
+use crate::dead_letter::{DeadLetterTarget, RejectionReason, ORIGINAL_ROUTING_KEY_HEADER};
+use crate::metrics::{
+    BYTES_BROADCAST, DEAD_LETTERED, MESSAGES_BROADCAST, MESSAGES_CONSUMED, MESSAGES_UNACKED,
+    PASSTHROUGH_REJECTIONS,
+};
+use crate::recorder::Recorder;
+use crate::tap::{TapDecision, TapOutcome};
+use crate::undelivered::{requeue, Undelivered, UndeliveredHandler};
 use intersect_ingress_proxy_common::intersect_messaging::INTERSECT_MESSAGE_EXCHANGE;
-use intersect_ingress_proxy_common::protocols::amqp::{get_channel, get_connection, make_exchange};
+use intersect_ingress_proxy_common::protocols::amqp::{
+    compliant_queue_name, get_channel, is_routing_key_compliant, make_exchange, string_headers,
+    SharedConnection,
+};
 use intersect_ingress_proxy_common::{
-    configuration::BrokerSettings,
-    intersect_messaging::{make_eventsource_data, should_message_passthrough},
-    signals::wait_for_os_signal,
+    health::{HealthState, BROKER_COMPONENT, HEARTBEAT_INTERVAL},
+    intersect_messaging::{make_eventsource_data_with_metadata, should_message_passthrough},
+    signals::Shutdown,
+    telemetry::{inject_span_context, set_span_parent},
 };
 
-pub async fn broker_consumer_loop(
-    config_broker: BrokerSettings,
+/// Lets other parts of the application (i.e. the admin API) pause and resume consumption from the broker.
+/// While paused, messages stay on the broker.
+pub struct ConsumerControl {
+    paused: watch::Sender<bool>,
+    /// whether we currently have a consumer on the queue
+    consuming: AtomicBool,
+}
+
+impl ConsumerControl {
+    pub fn new() -> Arc<Self> {
+        let (paused, _) = watch::channel(false);
+        Arc::new(Self {
+            paused,
+            consuming: AtomicBool::new(false),
+        })
+    }
+
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// false while paused, disconnected or waiting for subscribers
+    pub fn is_consuming(&self) -> bool {
+        self.consuming.load(Ordering::Relaxed)
+    }
+
+    fn subscribe(&self) -> watch::Receiver<bool> {
+        self.paused.subscribe()
+    }
+}
+
+/// Fill in the queue name template, hashing the result if it's not a valid AMQP queue name
+fn queue_name(config_queue: &QueueSettings, config_topic: &str) -> anyhow::Result<String> {
+    let mut name = config_queue
+        .name_template
+        .replace("{topic_prefix}", config_topic);
+    if name.contains("{instance_id}") {
+        let Some(instance_id) = &config_queue.instance_id else {
+            anyhow::bail!(
+                "queue.name_template uses {{instance_id}} but queue.instance_id is not set"
+            );
+        };
+        name = name.replace("{instance_id}", instance_id);
+    }
+    if name.is_empty() {
+        anyhow::bail!("queue.name_template must not be empty");
+    }
+    Ok(compliant_queue_name(&name))
+}
+
+/// Fill in the routing key patterns our queue should be bound to. This fails if any pattern is invalid.
+fn binding_keys(config_queue: &QueueSettings, config_topic: &str) -> anyhow::Result<Vec<String>> {
+    if config_queue.binding_keys.is_empty() {
+        anyhow::bail!("queue.binding_keys must not be empty, we would never receive any messages");
+    }
+    config_queue
+        .binding_keys
+        .iter()
+        .map(|key| {
+            let key = key.replace("{topic_prefix}", config_topic);
+            if is_routing_key_compliant(&key) {
+                Ok(key)
+            } else {
+                Err(anyhow::anyhow!(
+                    "binding key {} is not a valid AMQP routing key",
+                    key
+                ))
+            }
+        })
+        .collect()
+}
+
+/// Build the queue declaration from the configuration. This fails if the name template can't be filled in.
+fn queue_declare_arguments(
+    config_queue: &QueueSettings,
+    config_topic: &str,
+) -> anyhow::Result<QueueDeclareArguments> {
+    let name = queue_name(config_queue, config_topic)?;
+
+    let mut arguments = FieldTable::new();
+    if let Some(max_length) = config_queue.max_length {
+        arguments.insert(
+            "x-max-length".try_into().unwrap(),
+            FieldValue::l(max_length.into()),
+        );
+    }
+    if let Some(message_ttl_ms) = config_queue.message_ttl_ms {
+        arguments.insert(
+            "x-message-ttl".try_into().unwrap(),
+            FieldValue::l(message_ttl_ms.into()),
+        );
+    }
+
+    Ok(QueueDeclareArguments::new(&name)
+        .durable(config_queue.durable)
+        .exclusive(config_queue.exclusive)
+        .auto_delete(config_queue.auto_delete)
+        .arguments(arguments)
+        .finish())
+}
+
+/// Everything we declare on the broker besides the INTERSECT exchange, and how we consume from it.
+/// Resolved from the configuration up front.
+pub struct ConsumerTopology {
+    consumer: ConsumerSettings,
+    no_subscriber: NoSubscriberSettings,
+    queue: QueueDeclareArguments,
+    binding_keys: BindingKeys,
+    dead_letter: Option<DeadLetterTarget>,
+    recorder: Option<Recorder>,
+}
+
+/// The routing key patterns our queue is bound to, which can change while we consume
+#[derive(Clone)]
+pub struct BindingKeys(Arc<watch::Sender<Vec<String>>>);
+
+impl BindingKeys {
+    /// Rebind the queue to the patterns in a reloaded configuration, bindings which were removed are unbound.
+    pub fn reload(&self, configuration: &Settings) {
+        match binding_keys(&configuration.queue, &configuration.topic_prefix) {
+            Ok(keys) => {
+                self.0.send_replace(keys);
+            }
+            Err(e) => tracing::error!(error = ?e, "not changing the queue's bindings"),
+        }
+    }
+}
+
+impl ConsumerTopology {
+    /// This fails if the queue configuration is invalid, or the recording can't be opened.
+    pub fn new(configuration: &Settings) -> anyhow::Result<Self> {
+        Ok(Self {
+            consumer: configuration.consumer.clone(),
+            no_subscriber: configuration.no_subscriber.clone(),
+            queue: queue_declare_arguments(&configuration.queue, &configuration.topic_prefix)?,
+            binding_keys: BindingKeys(Arc::new(watch::Sender::new(binding_keys(
+                &configuration.queue,
+                &configuration.topic_prefix,
+            )?))),
+            dead_letter: configuration
+                .dead_letter
+                .as_ref()
+                .map(|settings| DeadLetterTarget::new(settings, &configuration.topic_prefix)),
+            recorder: match &configuration.recording {
+                Some(settings) => Some(
+                    Recorder::start(settings)
+                        .with_context(|| format!("could not open recording {}", settings.path))?,
+                ),
+                None => None,
+            },
+        })
+    }
+
+    /// lets the binding keys change while we consume, see "BindingKeys::reload"
+    pub fn binding_keys(&self) -> BindingKeys {
+        self.binding_keys.clone()
+    }
+}
+
+/// Make the queue's bindings match "keys", binding the new patterns before unbinding the old ones.
+/// Failures are only logged, bindings are made again whenever we reconnect.
+async fn rebind(channel: &Channel, queue_name: &str, bound: &mut Vec<String>, keys: Vec<String>) {
+    for key in keys.iter().filter(|key| !bound.contains(key)) {
+        match channel
+            .queue_bind(QueueBindArguments::new(
+                queue_name,
+                INTERSECT_MESSAGE_EXCHANGE,
+                key,
+            ))
+            .await
+        {
+            Ok(()) => tracing::info!("bound queue {} to {}", queue_name, key),
+            Err(e) => tracing::error!(error = ?e, "could not bind queue {} to {}", queue_name, key),
+        }
+    }
+    for key in bound.iter().filter(|key| !keys.contains(key)) {
+        match channel
+            .queue_unbind(QueueUnbindArguments::new(
+                queue_name,
+                INTERSECT_MESSAGE_EXCHANGE,
+                key,
+            ))
+            .await
+        {
+            Ok(()) => tracing::info!("unbound queue {} from {}", queue_name, key),
+            Err(e) => {
+                tracing::error!(error = ?e, "could not unbind queue {} from {}", queue_name, key)
+            }
+        }
+    }
+    *bound = keys;
+}
+
+/// Consume from our queue on a channel of the shared connection, which is left open when this stops.
+/// Once shutdown is requested, we stop consuming and wait for subscribers to confirm what they were sent before closing the channel.
+pub fn broker_consumer_loop(
+    connection: Arc<SharedConnection>,
     config_topic: String,
+    topology: ConsumerTopology,
     broadcaster: Arc<Broadcaster>,
-) -> tokio::task::JoinHandle<()> {
-    tokio::spawn(async move {
-        broker_consumer_loop_inner(config_broker, config_topic, broadcaster).await
-    })
+    health: Arc<HealthState>,
+    consumer_control: Arc<ConsumerControl>,
+    shutdown: Shutdown,
+) {
+    shutdown.clone().spawn(async move {
+        broker_consumer_loop_inner(
+            connection,
+            config_topic,
+            topology,
+            broadcaster,
+            health,
+            consumer_control,
+            shutdown,
+        )
+        .await
+    });
 }
 
 async fn broker_consumer_loop_inner(
-    config_broker: BrokerSettings,
+    shared_connection: Arc<SharedConnection>,
     config_topic: String,
+    topology: ConsumerTopology,
     broadcaster: Arc<Broadcaster>,
+    health: Arc<HealthState>,
+    consumer_control: Arc<ConsumerControl>,
+    shutdown: Shutdown,
 ) {
     let mut connected_once = false;
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
 
     'connection_loop: loop {
-        let connection = get_connection(&config_broker, if connected_once { 0 } else { 10 }).await;
+        let connection = shared_connection
+            .get(if connected_once { 0 } else { 10 })
+            .await;
         let channel = get_channel(&connection).await;
         connected_once = true;
 
+        // bound how many unacknowledged messages the broker pushes to us
+        channel
+            .basic_qos(BasicQosArguments::new(
+                0,
+                topology.consumer.prefetch_count,
+                false,
+            ))
+            .await
+            .expect("Couldn't set prefetch count");
+        // delivery tags are per channel, so the batcher is too
+        let acker = AckBatcher::new(
+            topology.consumer.ack_batch_size,
+            Duration::from_millis(topology.consumer.ack_interval_ms.max(1)),
+        );
+        let mut ack_interval = tokio::time::interval(acker.interval());
+
         make_exchange(&channel)
             .await
             .expect("Could not declare exchange on channel");
 
-        // we'll use a persistent queue named "broker-2-http", as there should only be one broker-2-http deployment per System
-        // TODO - note that we should probably name queues larger than 127 characters with a hashed key
+        if let Some(dead_letter) = &topology.dead_letter {
+            dead_letter
+                .declare(&channel)
+                .await
+                .expect("Couldn't declare dead-letter exchange and queue");
+        }
+
+        // by default, a persistent queue named after our System, so multiple Systems can share a broker
         let (queue_name, _, _) = channel
-            .queue_declare(QueueDeclareArguments::durable_client_named("broker-2-http"))
+            .queue_declare(topology.queue.clone())
             .await
             .expect("Couldn't declare queue")
             .expect("didn't get correct args back from queue declaration");
 
-        // listen for every single message on the exchange, we must do this due to the way userspace messages work
-        channel
-            .queue_bind(QueueBindArguments::new(
-                &queue_name,
-                INTERSECT_MESSAGE_EXCHANGE,
-                "#",
-            ))
+        // only have the broker send us messages we might forward, we still check the source of each message ourselves.
+        // NOTE: bindings removed from the configuration while we were not running stay on a durable queue until removed on the broker.
+        let mut binding_keys_rx = topology.binding_keys.0.subscribe();
+        let mut bound = binding_keys_rx.borrow_and_update().clone();
+        for binding_key in &bound {
+            channel
+                .queue_bind(QueueBindArguments::new(
+                    &queue_name,
+                    INTERSECT_MESSAGE_EXCHANGE,
+                    binding_key,
+                ))
+                .await
+                .expect("Couldn't bind to queue");
+        }
+
+        // results of messages WebSocket subscribers have to confirm, these are per channel like the delivery tags
+        let (confirmations, mut confirmed_rx) = mpsc::unbounded_channel();
+        let mut handler = MessageHandler {
+            channel: channel.clone(),
+            connection: connection.clone(),
+            acker,
+            confirmations,
+            awaiting_confirmation: 0,
+            undelivered: UndeliveredHandler::new(&topology.no_subscriber, &queue_name),
+            config_topic: &config_topic,
+            broadcaster: &broadcaster,
+            dead_letter: topology.dead_letter.as_ref(),
+            recorder: topology.recorder.as_ref(),
+            health: &health,
+        };
+        handler
+            .undelivered
+            .declare(&channel)
             .await
-            .expect("Couldn't bind to queue");
+            .expect("Couldn't declare delay queue");
 
-        // Do NOT automatically acknowledge messages, we may not be able to forward them.
-        let args = BasicConsumeArguments::new(&queue_name, &Uuid::new_v4().to_string())
-            .manual_ack(true) // only ack messages we should actually publish, we will nack the others
-            .finish();
+        health.set_connected(BROKER_COMPONENT, true);
+        let mut paused_rx = consumer_control.subscribe();
+        let mut client_count_rx = broadcaster.watch_client_count();
+        // set when a message couldn't be delivered with the "hold" policy, until a subscriber connects
+        let mut holding = false;
+        let min_subscribers = topology.consumer.min_subscribers;
 
-        let (consumer_tag, mut messages_rx) = channel.basic_consume_rx(args).await.unwrap();
-        loop {
-            tokio::select! {
-                // OS kill signal
-                _ = wait_for_os_signal() => {
-                    // attempt cleanup before terminating
-                    tracing::warn!("Received terminate signal from OS, attempting to gracefully disconnect from AMQP broker...");
-                    cleanup(consumer_tag, channel, connection).await;
+        // each iteration is a single consumer on the channel, we only leave this loop to reconnect or shut down
+        let consumer_tag = 'consume_loop: loop {
+            // while an operator has paused us (or there's nobody to send messages to), messages will accumulate on the broker instead
+            loop {
+                let paused = *paused_rx.borrow_and_update();
+                let client_count = *client_count_rx.borrow_and_update();
+                if !should_hold_consumption(paused, client_count, min_subscribers, holding) {
+                    break;
+                }
+                tracing::debug!(
+                    "Not consuming from the broker (paused: {}, {} of {} required SSE subscribers connected)",
+                    paused,
+                    client_count,
+                    min_subscribers.max(holding as usize)
+                );
+                tokio::select! {
+                    _ = heartbeat.tick() => {
+                        health.heartbeat();
+                        if !channel.is_open() {
+                            tracing::warn!("Channel was closed while consumption was paused, will try to reconnect");
+                            break 'consume_loop None;
+                        }
+                    },
+                    // subscribers keep confirming what they were sent, and requeued messages are nacked, so acknowledgements are still due
+                    due = next_due(&mut ack_interval, &mut confirmed_rx) => handler.settle(due).await,
+                    _ = handler.undelivered.next_requeue() => handler.requeue_due().await,
+                    _ = shutdown.requested() => {
+                        tracing::warn!("Shutting down, attempting to gracefully disconnect from AMQP broker...");
+                        handler.drain(&mut confirmed_rx, &shutdown).await;
+                        health.set_connected(BROKER_COMPONENT, false);
+                        handler.acker.flush(&channel).await;
+                        handler.undelivered.requeue_all(&channel, &mut handler.acker).await;
+                        cleanup(None, channel).await;
 
-                    break 'connection_loop;
-                },
-                consumer_result = messages_rx.recv() => {
-                    match consumer_result {
-                        Some(msg) => consume_message(msg, &channel, &config_topic, broadcaster.clone()).await,
-                        None => {
-                            tracing::warn!("Messages channel was suddenly closed, will try to reconnect");
-                            break;
-                        },
+                        break 'connection_loop;
+                    },
+                    _ = paused_rx.changed() => {},
+                    _ = client_count_rx.changed() => {},
+                    Ok(()) = binding_keys_rx.changed() => {
+                        let keys = binding_keys_rx.borrow_and_update().clone();
+                        rebind(&channel, &queue_name, &mut bound, keys).await;
+                    },
+                }
+            }
+            holding = false;
+
+            // Do NOT automatically acknowledge messages, we may not be able to forward them.
+            let args = BasicConsumeArguments::new(&queue_name, &Uuid::new_v4().to_string())
+                .manual_ack(true) // only ack messages we should actually publish, we will nack the others
+                .finish();
+
+            let (consumer_tag, mut messages_rx) = channel.basic_consume_rx(args).await.unwrap();
+            consumer_control.consuming.store(true, Ordering::Relaxed);
+            tracing::info!("Consuming messages from queue {}", queue_name);
+            loop {
+                tokio::select! {
+                    _ = heartbeat.tick() => health.heartbeat(),
+                    due = next_due(&mut ack_interval, &mut confirmed_rx) => handler.settle(due).await,
+                    _ = handler.undelivered.next_requeue() => handler.requeue_due().await,
+                    _ = shutdown.requested() => {
+                        // attempt cleanup before terminating
+                        tracing::warn!("Shutting down, attempting to gracefully disconnect from AMQP broker...");
+                        consumer_control.consuming.store(false, Ordering::Relaxed);
+                        handler.stop_consuming(&consumer_tag, &mut messages_rx).await;
+                        handler.drain(&mut confirmed_rx, &shutdown).await;
+                        health.set_connected(BROKER_COMPONENT, false);
+                        handler.acker.flush(&channel).await;
+                        handler.undelivered.requeue_all(&channel, &mut handler.acker).await;
+                        cleanup(None, channel).await;
+
+                        break 'connection_loop;
+                    },
+                    _ = paused_rx.changed() => {
+                        if *paused_rx.borrow() {
+                            tracing::warn!("Pausing consumption from the broker");
+                            consumer_control.consuming.store(false, Ordering::Relaxed);
+                            handler.stop_consuming(&consumer_tag, &mut messages_rx).await;
+                            continue 'consume_loop;
+                        }
+                    },
+                    Ok(()) = binding_keys_rx.changed() => {
+                        let keys = binding_keys_rx.borrow_and_update().clone();
+                        rebind(&channel, &queue_name, &mut bound, keys).await;
+                    },
+                    _ = client_count_rx.changed() => {
+                        if *client_count_rx.borrow() < min_subscribers {
+                            tracing::warn!("Fewer than {} SSE subscriber(s) connected, no longer consuming from the broker", min_subscribers);
+                            consumer_control.consuming.store(false, Ordering::Relaxed);
+                            handler.stop_consuming(&consumer_tag, &mut messages_rx).await;
+                            continue 'consume_loop;
+                        }
+                    },
+                    consumer_result = messages_rx.recv() => {
+                        match consumer_result {
+                            Some(msg) => {
+                                if handler.handle(msg).await {
+                                    tracing::warn!("Nobody is subscribed, holding consumption from the broker until somebody subscribes");
+                                    holding = true;
+                                    consumer_control.consuming.store(false, Ordering::Relaxed);
+                                    handler.stop_consuming(&consumer_tag, &mut messages_rx).await;
+                                    continue 'consume_loop;
+                                }
+                            },
+                            None => {
+                                tracing::warn!("Messages channel was suddenly closed, will try to reconnect");
+                                break 'consume_loop Some(consumer_tag);
+                            },
+                        }
                     }
                 }
             }
+        };
+
+        // if we reach this, the channel has been closed (most likely from a broker disconnect), so we will clean up and then attempt reconnection
+        consumer_control.consuming.store(false, Ordering::Relaxed);
+        health.set_connected(BROKER_COMPONENT, false);
+        cleanup(consumer_tag, channel).await;
+    }
+}
+
+/// Whether we should not have a consumer on our queue right now.
+/// "holding" is set once a message couldn't be delivered with the "hold" no-subscriber policy.
+fn should_hold_consumption(
+    paused: bool,
+    client_count: usize,
+    min_subscribers: usize,
+    holding: bool,
+) -> bool {
+    paused || client_count < min_subscribers || (holding && client_count == 0)
+}
+
+/// Handles messages on a single channel, delivery tags are only meaningful on the channel which received them
+struct MessageHandler<'a> {
+    channel: Channel,
+    /// dead letters are published on their own confirmed channels
+    connection: Connection,
+    acker: AckBatcher,
+    /// given to the broadcaster with every message, so WebSocket subscribers can confirm it
+    confirmations: UnboundedSender<Confirmed>,
+    /// number of messages we're waiting on "confirmations" for
+    awaiting_confirmation: usize,
+    undelivered: UndeliveredHandler,
+    config_topic: &'a str,
+    broadcaster: &'a Arc<Broadcaster>,
+    dead_letter: Option<&'a DeadLetterTarget>,
+    recorder: Option<&'a Recorder>,
+    health: &'a HealthState,
+}
+
+impl MessageHandler<'_> {
+    /// Returns true if nobody received the message and we should stop consuming until somebody subscribes
+    async fn handle(&mut self, msg: ConsumerMessage) -> bool {
+        let span = consume_span(&msg);
+        let consumed = consume_message(msg, self).instrument(span.clone()).await;
+        self.health.record_message();
+        match consumed {
+            Consumed::Done => false,
+            Consumed::AwaitingConfirmation => {
+                self.awaiting_confirmation += 1;
+                false
+            }
+            Consumed::Undelivered(undelivered) => {
+                self.undelivered
+                    .handle(&self.channel, &mut self.acker, *undelivered)
+                    .instrument(span)
+                    .await
+            }
+        }
+    }
+
+    async fn settle(&mut self, due: Due) {
+        match due {
+            Due::Acks => self.acker.flush(&self.channel).await,
+            Due::Confirmed(confirmed) => self.confirmed(confirmed).await,
+        }
+    }
+
+    async fn confirmed(&mut self, confirmed: Confirmed) {
+        self.awaiting_confirmation = self.awaiting_confirmation.saturating_sub(1);
+        settle_confirmation(&self.channel, &mut self.acker, confirmed).await;
+    }
+
+    /// On shutdown, wait for subscribers to confirm every message they were sent, until the shutdown deadline passes.
+    /// Messages still unconfirmed then stay unacknowledged, the broker redelivers them once we close the channel.
+    async fn drain(
+        &mut self,
+        confirmed_rx: &mut UnboundedReceiver<Confirmed>,
+        shutdown: &Shutdown,
+    ) {
+        while self.awaiting_confirmation > 0 {
+            tokio::select! {
+                Some(confirmed) = confirmed_rx.recv() => self.confirmed(confirmed).await,
+                _ = shutdown.deadline_passed() => {
+                    tracing::warn!(
+                        "abandoning {} messages subscribers did not confirm in time, the broker will redeliver them",
+                        self.awaiting_confirmation
+                    );
+                    return;
+                },
+            }
+        }
+    }
+
+    async fn requeue_due(&mut self) {
+        self.undelivered
+            .requeue_due(&self.channel, &mut self.acker)
+            .await
+    }
+
+    /// Cancel the consumer, handling any messages the broker sent us before the cancel
+    async fn stop_consuming(
+        &mut self,
+        consumer_tag: &str,
+        messages_rx: &mut UnboundedReceiver<ConsumerMessage>,
+    ) {
+        if let Err(e) = self
+            .channel
+            .basic_cancel(BasicCancelArguments::new(consumer_tag))
+            .await
+        {
+            tracing::error!(error = ?e, "could not send cancel message");
+        };
+        while let Some(msg) = messages_rx.recv().await {
+            self.handle(msg).await;
         }
+        self.acker.flush(&self.channel).await;
+    }
+}
 
-        // if we reach this, the channel has been closed from the messages_rx object (most likely from a broker disconnect), so we will clean up and then attempt reconnection
-        cleanup(consumer_tag, channel, connection).await;
+/// Bookkeeping which is due whether or not we are consuming
+enum Due {
+    /// the acknowledgements collected so far should be sent
+    Acks,
+    Confirmed(Confirmed),
+}
+
+/// Wait for the next acknowledgement interval or subscriber confirmation. Both the consuming and the held loop wait on this,
+/// so confirmations which arrive while consumption is paused (or nobody is subscribed) are still acknowledged.
+async fn next_due(
+    ack_interval: &mut tokio::time::Interval,
+    confirmed_rx: &mut UnboundedReceiver<Confirmed>,
+) -> Due {
+    tokio::select! {
+        _ = ack_interval.tick() => Due::Acks,
+        Some(confirmed) = confirmed_rx.recv() => Due::Confirmed(confirmed),
     }
 }
 
-/// domain logic for handling a message from the broker
-async fn consume_message(
-    msg: ConsumerMessage,
-    channel: &Channel,
-    config_topic: &str,
-    broadcaster: Arc<Broadcaster>,
+/// Every subscriber which had to confirm a message has answered: acknowledge it if they all published it, requeue it otherwise
+async fn settle_confirmation(
+    channel: &impl Acknowledge,
+    acker: &mut AckBatcher,
+    confirmed: Confirmed,
 ) {
+    if confirmed.confirmed {
+        acker.release(confirmed.delivery_tag);
+        acker.ack(channel, confirmed.delivery_tag).await;
+    } else {
+        tracing::warn!(
+            "delivery tag {} was not confirmed by every WebSocket subscriber, requeueing it",
+            confirmed.delivery_tag
+        );
+        requeue(channel, acker, confirmed.delivery_tag, "unconfirmed").await;
+    }
+}
+
+/// Each message gets its own span. If whoever published the message included a trace context in the AMQP headers, we continue that trace.
+fn consume_span(msg: &ConsumerMessage) -> tracing::Span {
+    let span = tracing::info_span!(
+        "consume_message",
+        routing_key = msg.deliver.as_ref().map(|d| d.routing_key().as_str()),
+    );
+    let headers = msg.basic_properties.as_ref().and_then(|p| p.headers());
+    set_span_parent(&span, &string_headers(headers));
+    span
+}
+
+/// The routing key the message was originally published with.
+/// Messages coming back from the delay queue are delivered with our queue name as the routing key instead.
+fn original_routing_key(deliver: &Deliver, properties: Option<&BasicProperties>) -> String {
+    if deliver.exchange().is_empty() {
+        if let Some(routing_key) =
+            string_headers(properties.and_then(|p| p.headers())).remove(ORIGINAL_ROUTING_KEY_HEADER)
+        {
+            return routing_key;
+        }
+    }
+    deliver.routing_key().to_owned()
+}
+
+/// what became of a message from the broker
+enum Consumed {
+    /// acknowledged (or not acknowledged on purpose), nothing left to do
+    Done,
+    /// held until subscribers confirm it, see "MessageHandler::confirmed"
+    AwaitingConfirmation,
+    /// nobody received it, so the no-subscriber policy applies
+    Undelivered(Box<Undelivered>),
+}
+
+/// domain logic for handling a message from the broker.
+async fn consume_message(msg: ConsumerMessage, handler: &mut MessageHandler<'_>) -> Consumed {
+    let channel = &handler.channel;
+    let acker = &mut handler.acker;
+    let broadcaster = handler.broadcaster;
+    let dead_letter = handler.dead_letter;
     let deliver = msg.deliver.unwrap();
     let content = msg.content.unwrap();
+    MESSAGES_CONSUMED.inc();
+    let routing_key = original_routing_key(&deliver, msg.basic_properties.as_ref());
 
     // This is the major difference between our implementations and what the SDK does - we don't necessarily want to ACK (but by default we will)
-    // we will always manually ACK unless nobody was available to listen to our message, in which case we should NACK and requeue.
-    let mut should_ack = true;
+    // we will always manually ACK unless nobody was available to listen to our message, in which case the no-subscriber policy decides what happens,
+    // or a rejected message could not be dead-lettered, in which case it is requeued.
     if deliver.redelivered() {
         tracing::warn!("message was redelivered");
     }
     tracing::debug!("consume delivery {}", deliver);
-    match String::from_utf8(content) {
+    // if we won't forward the message, why and the original message content
+    let rejection = match String::from_utf8(content) {
         Ok(utf8_data) => {
             tracing::debug!("raw message data: {}", &utf8_data);
-            match should_message_passthrough(&utf8_data, config_topic) {
+            match should_message_passthrough(&utf8_data, handler.config_topic) {
                 Err(e) => {
                     tracing::error!(error = ?e, "message is valid UTF-8 but not INTERSECT JSON");
+                    Some((RejectionReason::InvalidJson, utf8_data.into_bytes()))
                 }
                 Ok(false) => {
                     tracing::warn!("message source is not from this system, will not broadcast it");
+                    Some((RejectionReason::ForeignSource, utf8_data.into_bytes()))
                 }
                 Ok(true) => {
-                    let topic = deliver.routing_key();
-                    let event = make_eventsource_data(topic, &utf8_data);
+                    // forward the trace context so http-2-broker can continue it on the other side
+                    let metadata = inject_span_context(&tracing::Span::current());
+                    let event =
+                        make_eventsource_data_with_metadata(&routing_key, &utf8_data, &metadata);
                     tracing::debug!("consume delivery {} , data: {}", deliver, event,);
-                    // TODO handle this better later, see broadcast() documentation for details.
-                    if broadcaster.broadcast(&event) == 0 {
-                        tracing::warn!("Broadcaster did not broadcast to anybody");
-                        should_ack = false;
+                    let confirm = ConfirmTarget {
+                        delivery_tag: deliver.delivery_tag(),
+                        done: handler.confirmations.clone(),
+                    };
+                    let outcome = broadcaster.broadcast(&event, Some(confirm)).await;
+                    if outcome.delivered == 0 {
+                        tracing::warn!(
+                            "Broadcaster did not broadcast to anybody, nobody got delivery {}",
+                            deliver
+                        );
+                        broadcaster.tap().publish(
+                            &routing_key,
+                            TapDecision::Passthrough,
+                            TapOutcome::Undelivered,
+                            utf8_data.as_bytes(),
+                        );
+                        return Consumed::Undelivered(Box::new(Undelivered {
+                            delivery_tag: deliver.delivery_tag(),
+                            routing_key,
+                            properties: msg.basic_properties,
+                            content: utf8_data.into_bytes(),
+                        }));
+                    }
+                    MESSAGES_BROADCAST.inc();
+                    BYTES_BROADCAST.inc_by(event.len() as u64);
+                    if let Some(recorder) = handler.recorder {
+                        recorder.record(&routing_key, &utf8_data);
+                    }
+                    let awaiting_confirmation = outcome.awaiting_confirmation > 0;
+                    broadcaster.tap().publish(
+                        &routing_key,
+                        TapDecision::Passthrough,
+                        if awaiting_confirmation {
+                            TapOutcome::AwaitingConfirmation
+                        } else {
+                            TapOutcome::Delivered
+                        },
+                        utf8_data.as_bytes(),
+                    );
+                    if awaiting_confirmation {
+                        // acknowledged (or requeued) once every WebSocket subscriber has answered
+                        acker.hold(deliver.delivery_tag());
+                        return Consumed::AwaitingConfirmation;
                     }
+                    None
                 }
             }
         }
         Err(e) => {
             tracing::error!(error = ?e, "message data is not UTF-8, cannot be forwarded over SSE");
+            Some((RejectionReason::InvalidUtf8, e.into_bytes()))
         }
-    }
+    };
 
-    if should_ack {
-        tracing::debug!("ack to delivery {}", deliver);
-        let args = BasicAckArguments::new(deliver.delivery_tag(), false);
-        match channel.basic_ack(args).await {
-            Ok(_) => {}
-            Err(e) => tracing::error!(error = ?e, "manual ack did not work"),
-        };
-    } else {
-        // We don't acknowledge or reject the message, so we immediately get the message back.
-        tracing::warn!(
-            "Some clients probably did not get delivery {}, not acknowledging the message",
-            deliver,
-        );
-        // TODO - if we're able to determine SPECIFIC clients who did/did not get it, we may want to explicitly reject the message.
-        // match channel
-        //     .basic_reject(BasicRejectArguments::new(deliver.delivery_tag(), true))
-        //     .await
-        // {
-        //     Ok(_) => {}
-        //     Err(e) => tracing::error!(error = ?e, "manual nack did not work"),
-        // };
+    if let Some((reason, content)) = rejection {
+        PASSTHROUGH_REJECTIONS
+            .with_label_values(&[reason.as_str()])
+            .inc();
+        let mut outcome = TapOutcome::Dropped;
+        // the content goes to the dead-letter exchange, so keep a copy for the tap
+        let tapped = broadcaster.tap().is_active().then(|| content.clone());
+        if let Some(dead_letter) = dead_letter.filter(|dl| dl.handles(reason)) {
+            match dead_letter
+                .publish(
+                    &handler.connection,
+                    reason,
+                    deliver.exchange(),
+                    &routing_key,
+                    msg.basic_properties,
+                    content,
+                )
+                .await
+            {
+                Ok(_) => {
+                    DEAD_LETTERED.with_label_values(&[reason.as_str()]).inc();
+                    tracing::info!(
+                        "delivery {} was sent to dead-letter exchange {}",
+                        deliver,
+                        dead_letter.exchange
+                    );
+                    outcome = TapOutcome::DeadLettered;
+                }
+                Err(e) => {
+                    // put it back on the broker rather than losing it, we try dead-lettering it again when it is redelivered
+                    tracing::error!(error = ?e, "could not dead-letter delivery {}, requeueing it", deliver);
+                    outcome = TapOutcome::Requeued;
+                }
+            }
+        }
+        if let Some(tapped) = tapped {
+            broadcaster
+                .tap()
+                .publish(&routing_key, TapDecision::from(reason), outcome, &tapped);
+        }
+        if outcome == TapOutcome::Requeued {
+            MESSAGES_UNACKED.inc();
+            requeue(channel, acker, deliver.delivery_tag(), "dead_letter").await;
+            return Consumed::Done;
+        }
     }
+
+    acker.ack(channel, deliver.delivery_tag()).await;
+    Consumed::Done
 }
 
 /// call this if we were instructed to shut down or our channel suddenly disconnected.
-async fn cleanup(consumer_tag: String, channel: Channel, connection: Connection) {
-    if let Err(e) = channel
-        .basic_cancel(BasicCancelArguments::new(&consumer_tag))
-        .await
-    {
-        tracing::error!(error = ?e, "could not send cancel message");
-    };
+/// The consumer tag should be provided if we are currently consuming.
+/// The connection is shared, so whoever created it closes it.
+async fn cleanup(consumer_tag: Option<String>, channel: Channel) {
+    if let Some(consumer_tag) = consumer_tag {
+        if let Err(e) = channel
+            .basic_cancel(BasicCancelArguments::new(&consumer_tag))
+            .await
+        {
+            tracing::error!(error = ?e, "could not send cancel message");
+        };
+    }
     match channel.close().await {
         Ok(_) => tracing::debug!("closed channel"),
         Err(e) => {
             tracing::error!(error = ?e, "Could not close channel")
         }
     }
-    match connection.close().await {
-        Ok(_) => tracing::debug!("closeed connection"),
-        Err(e) => {
-            tracing::error!(error = ?e, "Could not close connection")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consumption_waits_for_subscribers() {
+        assert!(should_hold_consumption(false, 0, 1, false));
+        assert!(!should_hold_consumption(false, 1, 1, false));
+        assert!(should_hold_consumption(true, 1, 1, false));
+        assert!(should_hold_consumption(false, 2, 3, false));
+
+        // with no minimum, only the "hold" policy stops consumption
+        assert!(!should_hold_consumption(false, 0, 0, false));
+        assert!(should_hold_consumption(false, 0, 0, true));
+        assert!(!should_hold_consumption(false, 1, 0, true));
+    }
+
+    /// records what would have been sent to the broker
+    #[derive(Default)]
+    struct RecordedAcks(std::sync::Mutex<Vec<(u64, bool)>>);
+
+    impl Acknowledge for RecordedAcks {
+        async fn send_ack(
+            &self,
+            delivery_tag: u64,
+            multiple: bool,
+        ) -> Result<(), amqprs::error::Error> {
+            self.0.lock().unwrap().push((delivery_tag, multiple));
+            Ok(())
         }
+
+        async fn send_nack(&self, _: u64, _: bool) -> Result<(), amqprs::error::Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn confirmations_are_acknowledged_while_held() {
+        let channel = RecordedAcks::default();
+        let mut acker = AckBatcher::new(10, Duration::from_millis(10));
+        let mut ack_interval = tokio::time::interval(acker.interval());
+        let (confirmations, mut confirmed_rx) = mpsc::unbounded_channel();
+        // a subscriber confirms a message after consumption was held, with no more messages coming in
+        acker.hold(1);
+        confirmations
+            .send(Confirmed {
+                delivery_tag: 1,
+                confirmed: true,
+            })
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while channel.0.lock().unwrap().is_empty() {
+                match next_due(&mut ack_interval, &mut confirmed_rx).await {
+                    Due::Acks => acker.flush(&channel).await,
+                    Due::Confirmed(confirmed) => {
+                        settle_confirmation(&channel, &mut acker, confirmed).await
+                    }
+                }
+            }
+        })
+        .await
+        .expect("the confirmed message was never acknowledged");
+        assert_eq!(*channel.0.lock().unwrap(), [(1, true)]);
+    }
+
+    #[test]
+    fn queue_name_is_filled_in_from_template() {
+        let mut config_queue = QueueSettings::default();
+        assert_eq!(
+            queue_name(&config_queue, "organization.facility.system").unwrap(),
+            "broker-2-http.organization.facility.system"
+        );
+
+        config_queue.name_template = "broker-2-http.{topic_prefix}.{instance_id}".to_owned();
+        assert!(queue_name(&config_queue, "organization.facility.system").is_err());
+
+        config_queue.instance_id = Some("blue".to_owned());
+        assert_eq!(
+            queue_name(&config_queue, "organization.facility.system").unwrap(),
+            "broker-2-http.organization.facility.system.blue"
+        );
+    }
+
+    #[test]
+    fn binding_keys_are_filled_in() {
+        let mut config_queue = QueueSettings::default();
+        assert_eq!(
+            binding_keys(&config_queue, "organization.facility.system").unwrap(),
+            vec![
+                "organization.facility.system.#.lifecycle",
+                "organization.facility.system.#.events",
+                "#.userspace",
+            ]
+        );
+
+        config_queue.binding_keys = vec![];
+        assert!(binding_keys(&config_queue, "organization.facility.system").is_err());
+
+        config_queue.binding_keys = vec!["a".repeat(256)];
+        assert!(binding_keys(&config_queue, "organization.facility.system").is_err());
     }
 }
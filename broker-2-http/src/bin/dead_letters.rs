@@ -0,0 +1,207 @@
+/// Inspect and requeue messages broker-2-http sent to its dead-letter queue.
+/// Uses the same configuration (and command line options) as broker-2-http.
+///
+/// Usage:
+///   broker-2-http-dead-letters [OPTIONS] list [--limit LIMIT]     print up to LIMIT (default 20) dead-lettered messages, leaving them on the queue
+///   broker-2-http-dead-letters [OPTIONS] requeue [--limit LIMIT]  republish up to LIMIT (default: all) dead-lettered messages to their original exchange
+///   requeue only takes the messages which were on the queue when it started, as broker-2-http may dead-letter requeued messages again.
+///   i.e. broker-2-http-dead-letters -c broker-2-http/conf.yaml requeue --limit 10
+use amqprs::{
+    channel::{BasicAckArguments, BasicGetArguments, BasicNackArguments, QueueDeclareArguments},
+    BasicProperties,
+};
+use clap::{Parser, Subcommand};
+
+use broker_2_http::{
+    configuration::Settings,
+    dead_letter::{
+        strip_dead_letter_headers, DeadLetterTarget, CONFIRM_TIMEOUT, ORIGINAL_EXCHANGE_HEADER,
+        ORIGINAL_ROUTING_KEY_HEADER, REASON_HEADER,
+    },
+};
+use intersect_ingress_proxy_common::{
+    cli::Cli,
+    configuration::configuration_or_exit,
+    intersect_messaging::INTERSECT_MESSAGE_EXCHANGE,
+    protocols::amqp::{get_channel, get_connection, string_headers},
+    protocols::publish_confirm::publish_confirmed,
+};
+
+/// Inspect and requeue messages broker-2-http sent to its dead-letter queue
+#[derive(Parser)]
+#[command(name = "broker-2-http-dead-letters", version)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+    #[command(flatten)]
+    cli: Cli,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print dead-lettered messages, leaving them on the queue
+    List {
+        /// print at most this many messages
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Republish dead-lettered messages to their original exchange and routing key, without our headers.
+    /// A message is only removed from the dead-letter queue once the broker has confirmed its republished copy.
+    /// Only the messages on the queue when this starts are requeued, as broker-2-http may dead-letter them again.
+    Requeue {
+        /// requeue at most this many messages (default: all which are on the queue now)
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+}
+
+/// headers we need from a dead-lettered message, falling back to the routing key it was dead-lettered with
+fn original_destination(properties: &BasicProperties, routing_key: &str) -> (String, String) {
+    let headers = string_headers(properties.headers());
+    (
+        headers
+            .get(ORIGINAL_EXCHANGE_HEADER)
+            .cloned()
+            .unwrap_or_else(|| INTERSECT_MESSAGE_EXCHANGE.to_owned()),
+        headers
+            .get(ORIGINAL_ROUTING_KEY_HEADER)
+            .cloned()
+            .unwrap_or_else(|| routing_key.to_owned()),
+    )
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    args.cli.default_config = include_str!("../../conf.yaml");
+    let configuration = configuration_or_exit(&args.cli, Cli::read_configuration::<Settings>);
+    let Some(dead_letter_settings) = &configuration.dead_letter else {
+        anyhow::bail!("dead_letter is not configured");
+    };
+    let dead_letter = DeadLetterTarget::new(dead_letter_settings, &configuration.topic_prefix);
+
+    let connection = get_connection(&configuration.broker, 1).await;
+    let channel = get_channel(&connection).await;
+
+    match args.command {
+        Command::List { limit } => {
+            let mut last_tag = None;
+            for _ in 0..limit {
+                let Some((get_ok, properties, content)) = channel
+                    .basic_get(BasicGetArguments::new(&dead_letter.queue))
+                    .await?
+                else {
+                    break;
+                };
+                let (exchange, routing_key) =
+                    original_destination(&properties, get_ok.routing_key());
+                let reason = string_headers(properties.headers())
+                    .remove(REASON_HEADER)
+                    .unwrap_or_else(|| "unknown".to_owned());
+                println!(
+                    "reason={} exchange={} routing_key={}\n{}\n",
+                    reason,
+                    exchange,
+                    routing_key,
+                    String::from_utf8_lossy(&content)
+                );
+                last_tag = Some(get_ok.delivery_tag());
+            }
+            // put everything we looked at back on the queue
+            if let Some(last_tag) = last_tag {
+                channel
+                    .basic_nack(BasicNackArguments::new(last_tag, true, true))
+                    .await?;
+            }
+        }
+        Command::Requeue { limit } => {
+            // messages broker-2-http rejects again come straight back to the dead-letter queue,
+            // so only take what is there now rather than fetching until it is empty
+            let Some((_, queued, _)) = channel
+                .queue_declare(
+                    QueueDeclareArguments::new(&dead_letter.queue)
+                        .passive(true)
+                        .finish(),
+                )
+                .await?
+            else {
+                anyhow::bail!(
+                    "the broker did not report the size of {}",
+                    dead_letter.queue
+                );
+            };
+            let limit = limit.map_or(queued as usize, |limit| limit.min(queued as usize));
+            let mut requeued = 0;
+            while requeued < limit {
+                let Some((get_ok, mut properties, content)) = channel
+                    .basic_get(BasicGetArguments::new(&dead_letter.queue))
+                    .await?
+                else {
+                    break;
+                };
+                let (exchange, routing_key) =
+                    original_destination(&properties, get_ok.routing_key());
+                if let Some(headers) = properties.headers() {
+                    let headers = strip_dead_letter_headers(headers);
+                    properties.with_headers(headers);
+                }
+                let confirmed = publish_confirmed(
+                    &connection,
+                    properties,
+                    content,
+                    &exchange,
+                    &routing_key,
+                    CONFIRM_TIMEOUT,
+                )
+                .await;
+                if !matches!(confirmed, Ok(true)) {
+                    // keep the dead-lettered copy, the next "basic_get" would just return it again
+                    channel
+                        .basic_nack(BasicNackArguments::new(get_ok.delivery_tag(), false, true))
+                        .await?;
+                    eprintln!(
+                        "requeued {} messages, then the broker did not confirm a message for exchange {} with routing key {}, it was left on the dead-letter queue",
+                        requeued, exchange, routing_key
+                    );
+                    if let Err(e) = confirmed {
+                        eprintln!("{:?}", e);
+                    }
+                    std::process::exit(1);
+                }
+                channel
+                    .basic_ack(BasicAckArguments::new(get_ok.delivery_tag(), false))
+                    .await?;
+                requeued += 1;
+            }
+            println!("requeued {} messages", requeued);
+        }
+    }
+
+    channel.close().await?;
+    connection.close().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_take_the_shared_options() {
+        let args =
+            Args::try_parse_from(["dead-letters", "-c", "conf.yaml", "requeue", "--limit", "5"])
+                .unwrap();
+        assert_eq!(
+            args.cli.config.as_deref(),
+            Some(std::path::Path::new("conf.yaml"))
+        );
+        assert!(matches!(args.command, Command::Requeue { limit: Some(5) }));
+        assert!(matches!(
+            Args::try_parse_from(["dead-letters", "list"])
+                .unwrap()
+                .command,
+            Command::List { limit: 20 }
+        ));
+        assert!(Args::try_parse_from(["dead-letters", "purge"]).is_err());
+    }
+}
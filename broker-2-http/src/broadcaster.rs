@@ -1,36 +1,435 @@
-use axum::response::sse::Event;
-use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::{mpsc::UnboundedSender, watch, Notify},
+    time::Instant,
+};
+use uuid::Uuid;
+
+use crate::configuration::{OverflowPolicy, SubscriberQueueSettings};
+use crate::metrics::{LAGGED_MESSAGES, LAGGED_RECEIVERS, SLOW_CLIENTS_DISCONNECTED};
+use crate::tap::Tap;
+use intersect_ingress_proxy_common::signals::Shutdown;
+
+/// The result of a message which some clients had to confirm, sent back to the consumer which broadcast it
+#[derive(Debug, PartialEq, Eq)]
+pub struct Confirmed {
+    pub delivery_tag: u64,
+    /// false if any of the clients did not confirm the message (it rejected it, or disconnected or dropped it before confirming)
+    pub confirmed: bool,
+}
+
+/// Where to report the result of a message which clients have to confirm
+pub struct ConfirmTarget {
+    pub delivery_tag: u64,
+    pub done: UnboundedSender<Confirmed>,
+}
+
+/// Shared by every client which has to confirm a message. Once the last of them is dropped, the result is reported.
+struct ConfirmState {
+    target: ConfirmTarget,
+    failed: AtomicBool,
+    /// nothing is reported unless at least one client had to confirm the message
+    awaited: AtomicBool,
+}
+
+impl Drop for ConfirmState {
+    fn drop(&mut self) {
+        if self.awaited.load(Ordering::Relaxed) {
+            // the consumer may be gone (i.e. its channel closed), then the broker requeues the message anyways
+            let _ = self.target.done.send(Confirmed {
+                delivery_tag: self.target.delivery_tag,
+                confirmed: !self.failed.load(Ordering::Relaxed),
+            });
+        }
+    }
+}
+
+/// A single client's obligation to confirm a message. Dropping it without calling "confirm" rejects the message.
+struct PendingConfirmation {
+    state: Arc<ConfirmState>,
+    confirmed: bool,
+}
+
+impl PendingConfirmation {
+    fn new(state: &Arc<ConfirmState>) -> Self {
+        state.awaited.store(true, Ordering::Relaxed);
+        Self {
+            state: state.clone(),
+            confirmed: false,
+        }
+    }
+
+    fn confirm(mut self) {
+        self.confirmed = true;
+    }
+}
+
+impl Drop for PendingConfirmation {
+    fn drop(&mut self) {
+        if !self.confirmed {
+            self.state.failed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A message waiting to be sent to a single client
+pub struct QueuedEvent {
+    /// unique per broadcaster, so clients can refer to the message when confirming it
+    pub id: u64,
+    pub data: Arc<str>,
+    /// only set for clients which confirm messages
+    confirmation: Option<PendingConfirmation>,
+}
+
+/// Events waiting to be sent to a single client
+struct ClientQueue {
+    events: Mutex<VecDeque<QueuedEvent>>,
+    /// signalled when an event is added
+    added: Notify,
+    /// signalled when an event is removed or the client goes away, for the "block" overflow policy
+    removed: Notify,
+    /// set once the client is gone (or is being disconnected), nothing more should be added
+    closed: AtomicBool,
+}
+
+/// what happened when we tried to add an event to a client's queue
+#[derive(Debug, PartialEq, Eq)]
+enum Push {
+    Queued,
+    /// the event was queued, but the oldest queued event was dropped to make room
+    DroppedOldest,
+    /// the queue is full and the overflow policy says to disconnect the client
+    Overflowed,
+    /// the client is gone
+    Closed,
+    /// the queue is full, and shutdown was requested while we waited for room
+    ShuttingDown,
+}
+
+/// Everything we track about a single connected SSE client.
+pub struct ClientInfo {
+    pub id: Uuid,
+    /// the Basic Auth username the client connected with
+    pub identity: String,
+    pub connected_at: SystemTime,
+    /// number of events the client has been sent
+    pub messages_sent: AtomicU64,
+    /// number of events the client missed from falling too far behind the broadcaster
+    pub messages_lagged: AtomicU64,
+    /// number of events waiting to be sent to the client
+    pub backlog: AtomicU64,
+    /// whether the client confirms every message, so we only acknowledge messages on the broker once it has
+    pub confirms: bool,
+    disconnect: Notify,
+    queue: ClientQueue,
+    /// messages the client was sent but has not confirmed yet, by message id, with when they were sent
+    unconfirmed: Mutex<HashMap<u64, (Instant, PendingConfirmation)>>,
+}
+
+impl ClientInfo {
+    /// Resolves once somebody has called "Broadcaster::disconnect_client" on this client, or it fell too far behind.
+    pub async fn disconnect_requested(&self) {
+        self.disconnect.notified().await
+    }
+
+    /// The client published this message. Returns false if the message is unknown (i.e. it already timed out).
+    pub fn confirm(&self, id: u64) -> bool {
+        match self.unconfirmed.lock().unwrap().remove(&id) {
+            Some((_, confirmation)) => {
+                confirmation.confirm();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The client could not publish this message, so it gets requeued. Returns false if the message is unknown.
+    pub fn reject(&self, id: u64) -> bool {
+        self.unconfirmed.lock().unwrap().remove(&id).is_some()
+    }
+
+    /// Reject every message the client was sent more than "timeout" ago and still hasn't confirmed. Returns how many there were.
+    pub fn expire_unconfirmed(&self, timeout: Duration) -> usize {
+        let mut unconfirmed = self.unconfirmed.lock().unwrap();
+        let count = unconfirmed.len();
+        unconfirmed.retain(|_, (sent_at, _)| sent_at.elapsed() < timeout);
+        count - unconfirmed.len()
+    }
+
+    /// number of messages the client was sent but has not confirmed yet
+    pub fn unconfirmed_count(&self) -> usize {
+        self.unconfirmed.lock().unwrap().len()
+    }
+
+    /// whether the client has been sent everything queued for it, and confirmed all of it
+    pub fn is_drained(&self) -> bool {
+        self.backlog.load(Ordering::Relaxed) == 0 && self.unconfirmed_count() == 0
+    }
+
+    fn close(&self) {
+        self.queue.closed.store(true, Ordering::Relaxed);
+        // wake up the broadcaster if it's blocked on this client
+        self.queue.removed.notify_one();
+    }
+
+    async fn push(
+        &self,
+        id: u64,
+        event: &Arc<str>,
+        confirm: Option<&Arc<ConfirmState>>,
+        capacity: usize,
+        overflow: OverflowPolicy,
+        shutdown: &Shutdown,
+    ) -> Push {
+        loop {
+            {
+                let mut events = self.queue.events.lock().unwrap();
+                // checked while holding the lock, so nothing is added after the queue is cleared for good
+                if self.queue.closed.load(Ordering::Relaxed) {
+                    return Push::Closed;
+                }
+                let mut result = Push::Queued;
+                if events.len() >= capacity {
+                    match overflow {
+                        OverflowPolicy::Disconnect => return Push::Overflowed,
+                        OverflowPolicy::DropOldest => {
+                            // if the client had to confirm it, dropping it rejects it
+                            events.pop_front();
+                            self.messages_lagged.fetch_add(1, Ordering::Relaxed);
+                            result = Push::DroppedOldest;
+                        }
+                        OverflowPolicy::Block => {}
+                    }
+                }
+                if events.len() < capacity {
+                    events.push_back(QueuedEvent {
+                        id,
+                        data: event.clone(),
+                        confirmation: confirm
+                            .filter(|_| self.confirms)
+                            .map(PendingConfirmation::new),
+                    });
+                    self.backlog.store(events.len() as u64, Ordering::Relaxed);
+                    self.queue.added.notify_one();
+                    return result;
+                }
+            }
+            // notify_one stores a permit, so we can't miss the reader making room between the check and here
+            tokio::select! {
+                _ = self.queue.removed.notified() => {},
+                // a client which never makes room must not hold up shutdown
+                _ = shutdown.requested() => return Push::ShuttingDown,
+            }
+        }
+    }
+}
+
+/// all currently connected clients, and how many there are for anybody who wants to watch that
+struct Registry {
+    clients: Mutex<HashMap<Uuid, Arc<ClientInfo>>>,
+    count: watch::Sender<usize>,
+}
+
+impl Registry {
+    fn remove(&self, id: &Uuid) {
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.remove(id);
+            self.count.send_replace(clients.len());
+        }
+    }
+}
+
+type ClientRegistry = Arc<Registry>;
+
+/// The handle an SSE client uses to receive broadcasts. Dropping it removes the client from the registry.
+pub struct BroadcastClient {
+    pub info: Arc<ClientInfo>,
+    registry: ClientRegistry,
+}
+
+impl BroadcastClient {
+    /// Wait for the next event to send to the client. From now on, the client has to confirm it (if it confirms messages at all).
+    pub async fn recv(&self) -> QueuedEvent {
+        let queue = &self.info.queue;
+        loop {
+            {
+                let mut events = queue.events.lock().unwrap();
+                if let Some(mut event) = events.pop_front() {
+                    self.info
+                        .backlog
+                        .store(events.len() as u64, Ordering::Relaxed);
+                    queue.removed.notify_one();
+                    if let Some(confirmation) = event.confirmation.take() {
+                        self.info
+                            .unconfirmed
+                            .lock()
+                            .unwrap()
+                            .insert(event.id, (Instant::now(), confirmation));
+                    }
+                    return event;
+                }
+            }
+            queue.added.notified().await;
+        }
+    }
+
+    /// Wait for the next event, then keep collecting events until there are "max_messages" or "max_delay" has passed.
+    /// Events already collected are lost if this is cancelled, so only cancel it when the client is going away.
+    pub async fn recv_batch(&self, max_messages: usize, max_delay: Duration) -> Vec<QueuedEvent> {
+        let mut batch = vec![self.recv().await];
+        let deadline = Instant::now() + max_delay;
+        while batch.len() < max_messages {
+            match tokio::time::timeout_at(deadline, self.recv()).await {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+        batch
+    }
+}
+
+impl Drop for BroadcastClient {
+    fn drop(&mut self) {
+        self.info.close();
+        // reject anything the client still had to confirm right away, somebody else may hold on to the ClientInfo for a while
+        if let Ok(mut events) = self.info.queue.events.lock() {
+            events.clear();
+        }
+        if let Ok(mut unconfirmed) = self.info.unconfirmed.lock() {
+            unconfirmed.clear();
+        }
+        self.registry.remove(&self.info.id);
+    }
+}
+
+/// What happened to a single broadcast message
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BroadcastOutcome {
+    /// number of clients the message was queued for
+    pub delivered: usize,
+    /// number of clients who had their oldest queued message dropped to make room for this one
+    pub dropped_oldest: usize,
+    /// number of clients disconnected because their queue was full
+    pub disconnected: usize,
+    /// number of clients which have to confirm the message. If this is not 0, the result is sent to the ConfirmTarget later.
+    pub awaiting_confirmation: usize,
+}
 
-/// This broadcaster is an optimized implementation of a single-producer, multi-consumer channel.
 /// The Broadcaster is effectively the "link" between the broker and the HTTP gateway.
 /// If the broker decides to broadcast data, all SSE clients will asynchronosly receive it.
+/// Each client has its own bounded queue, so one slow client doesn't affect the others (unless the overflow policy is "block").
 pub struct Broadcaster {
-    fanout: broadcast::Sender<Event>,
-    // TODO - this variable only makes sense once BOTH of the following are true:
-    // 1) We have authentication/authorization set up (so randoms can't subscribe)
-    // 2) We have multiple expected SSE clients. With just one we don't need this.
-    //expected_client_count: usize,
+    /// all currently connected clients
+    clients: ClientRegistry,
+    /// maximum number of events queued for a single client
+    capacity: usize,
+    overflow: OverflowPolicy,
+    /// id of the next broadcast message
+    next_id: AtomicU64,
+    /// operators watching what the consumer does with messages, they don't count as clients
+    tap: Tap,
+    /// stops waiting for room in a client's queue (see the "block" overflow policy)
+    shutdown: Shutdown,
 }
 
 impl Broadcaster {
     /// Create the broadcaster. Note that it automatically wraps it in an Arc.
-    /// The broadcaster manages its producer but does not manage its consumers
-    pub fn new() -> Arc<Self> {
-        // use a fairly large channel capacity to account for potential receiver lags
-        let (tx, _) = broadcast::channel(256);
-        Arc::new(Broadcaster { fanout: tx })
+    /// The broadcaster manages its producer and keeps track of its consumers, but does not manage their lifetimes
+    pub fn new(settings: &SubscriberQueueSettings, shutdown: Shutdown) -> Arc<Self> {
+        Arc::new(Broadcaster {
+            clients: Arc::new(Registry {
+                clients: Mutex::new(HashMap::new()),
+                count: watch::channel(0).0,
+            }),
+            capacity: settings.capacity.max(1),
+            overflow: settings.overflow,
+            next_id: AtomicU64::new(0),
+            tap: Tap::default(),
+            shutdown,
+        })
+    }
+
+    /// See "/admin/tap"
+    pub fn tap(&self) -> &Tap {
+        &self.tap
     }
 
-    /// Add a broadcaster consumer - the calling function is responsible for cleaning up the consumer
-    pub fn add_client(&self) -> broadcast::Receiver<Event> {
-        self.fanout.subscribe()
+    /// Add a broadcaster consumer - the calling function is responsible for dropping the returned client once it disconnects.
+    /// If "confirms" is set, every message the client gets comes with a PendingConfirmation.
+    pub fn add_client(&self, identity: &str, confirms: bool) -> BroadcastClient {
+        let info = Arc::new(ClientInfo {
+            id: Uuid::new_v4(),
+            identity: identity.to_owned(),
+            connected_at: SystemTime::now(),
+            messages_sent: AtomicU64::new(0),
+            messages_lagged: AtomicU64::new(0),
+            backlog: AtomicU64::new(0),
+            confirms,
+            disconnect: Notify::new(),
+            queue: ClientQueue {
+                events: Mutex::new(VecDeque::new()),
+                added: Notify::new(),
+                removed: Notify::new(),
+                closed: AtomicBool::new(false),
+            },
+            unconfirmed: Mutex::new(HashMap::new()),
+        });
+        {
+            let mut clients = self.clients.clients.lock().unwrap();
+            clients.insert(info.id, info.clone());
+            self.clients.count.send_replace(clients.len());
+        }
+        BroadcastClient {
+            info,
+            registry: self.clients.clone(),
+        }
     }
 
-    /// Produce a message to be broadcast to all consumers. We handle the string -> SSE Event conversion here
-    /// so the consumer logic is minimal.
+    /// Snapshot of all currently connected clients
+    pub fn clients(&self) -> Vec<Arc<ClientInfo>> {
+        self.clients
+            .clients
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Look up a connected client
+    pub fn client(&self, id: &Uuid) -> Option<Arc<ClientInfo>> {
+        self.clients.clients.lock().unwrap().get(id).cloned()
+    }
+
+    /// Watch the number of connected clients, which is updated as clients connect and disconnect
+    pub fn watch_client_count(&self) -> watch::Receiver<usize> {
+        self.clients.count.subscribe()
+    }
+
+    /// Ask a connected client's stream to close. Returns false if no such client is connected.
+    pub fn disconnect_client(&self, id: &Uuid) -> bool {
+        match self.clients.clients.lock().unwrap().get(id) {
+            Some(client) => {
+                // notify_one stores a permit, so the client's stream sees this even if it isn't currently waiting
+                client.disconnect.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Queue a message for every connected client. With the "block" overflow policy, this waits until every client has room,
+    /// or shutdown is requested: clients without room are then disconnected, as with the "disconnect" policy.
     ///
-    /// This function returns the number of clients who got the message.
+    /// The outcome tells the caller how many clients got the message, so it can decide whether to acknowledge it.
+    /// If any client has to confirm the message, the caller should wait for the result to be sent to "confirm" instead.
     ///
     /// TODO - once we have multiple SSE clients, we may want to determine who missed out on what message.
     /// This probably involves:
@@ -39,7 +438,261 @@ impl Broadcaster {
     /// 3) Somehow transfer these messages over to the other message broker, make the messages their responsibility.
     ///
     /// Once the messages are on the other message broker, broker-2-http and http-2-broker don't need to care, handling them will be the SDK's job.
-    pub fn broadcast(&self, event: &str) -> usize {
-        self.fanout.send(Event::default().data(event)).unwrap_or(0)
+    pub async fn broadcast(&self, event: &str, confirm: Option<ConfirmTarget>) -> BroadcastOutcome {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let event: Arc<str> = event.into();
+        let confirm = confirm.map(|target| {
+            Arc::new(ConfirmState {
+                target,
+                failed: AtomicBool::new(false),
+                awaited: AtomicBool::new(false),
+            })
+        });
+        let mut outcome = BroadcastOutcome::default();
+        for client in self.clients() {
+            let result = client
+                .push(
+                    id,
+                    &event,
+                    confirm.as_ref(),
+                    self.capacity,
+                    self.overflow,
+                    &self.shutdown,
+                )
+                .await;
+            if client.confirms && matches!(result, Push::Queued | Push::DroppedOldest) {
+                outcome.awaiting_confirmation += 1;
+            }
+            match result {
+                Push::Queued => outcome.delivered += 1,
+                Push::DroppedOldest => {
+                    LAGGED_RECEIVERS.inc();
+                    LAGGED_MESSAGES.inc();
+                    outcome.delivered += 1;
+                    outcome.dropped_oldest += 1;
+                }
+                Push::Overflowed | Push::ShuttingDown => {
+                    LAGGED_RECEIVERS.inc();
+                    SLOW_CLIENTS_DISCONNECTED.inc();
+                    tracing::warn!(
+                        "SSE client {} has {} undelivered messages{}, disconnecting it",
+                        client.id,
+                        self.capacity,
+                        if result == Push::ShuttingDown {
+                            " and we are shutting down"
+                        } else {
+                            ""
+                        }
+                    );
+                    // stop counting it right away, its stream may not notice until the connection is closed
+                    client.close();
+                    client.disconnect.notify_one();
+                    self.clients.remove(&client.id);
+                    outcome.disconnected += 1;
+                }
+                Push::Closed => {}
+            }
+        }
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn broadcaster(capacity: usize, overflow: OverflowPolicy) -> Arc<Broadcaster> {
+        Broadcaster::new(
+            &SubscriberQueueSettings { capacity, overflow },
+            Shutdown::new(),
+        )
+    }
+
+    #[test]
+    fn clients_are_registered_until_dropped() {
+        let broadcaster = Broadcaster::new(&SubscriberQueueSettings::default(), Shutdown::new());
+        let client = broadcaster.add_client("dummy_username", false);
+        let id = client.info.id;
+        let count = broadcaster.watch_client_count();
+        assert_eq!(*count.borrow(), 1);
+
+        let clients = broadcaster.clients();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].identity, "dummy_username");
+        assert!(broadcaster.disconnect_client(&id));
+
+        drop(client);
+        assert!(broadcaster.clients().is_empty());
+        assert_eq!(*count.borrow(), 0);
+        assert!(!broadcaster.disconnect_client(&id));
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_oldest() {
+        let broadcaster = broadcaster(2, OverflowPolicy::DropOldest);
+        let client = broadcaster.add_client("dummy_username", false);
+
+        broadcaster.broadcast("1", None).await;
+        broadcaster.broadcast("2", None).await;
+        let outcome = broadcaster.broadcast("3", None).await;
+        assert_eq!(
+            outcome,
+            BroadcastOutcome {
+                delivered: 1,
+                dropped_oldest: 1,
+                disconnected: 0,
+                awaiting_confirmation: 0
+            }
+        );
+        assert_eq!(&*client.recv().await.data, "2");
+        assert_eq!(&*client.recv().await.data, "3");
+        assert_eq!(client.info.messages_lagged.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn full_queue_disconnects_slow_client() {
+        let broadcaster = broadcaster(1, OverflowPolicy::Disconnect);
+        let slow = broadcaster.add_client("slow", false);
+        let fast = broadcaster.add_client("fast", false);
+
+        broadcaster.broadcast("1", None).await;
+        fast.recv().await;
+        let outcome = broadcaster.broadcast("2", None).await;
+        assert_eq!(outcome.delivered, 1);
+        assert_eq!(outcome.disconnected, 1);
+        assert_eq!(*broadcaster.watch_client_count().borrow(), 1);
+        // the slow client is told to go away
+        tokio::time::timeout(Duration::from_secs(1), slow.info.disconnect_requested())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_queue_blocks_until_client_reads() {
+        let broadcaster = broadcaster(1, OverflowPolicy::Block);
+        let client = broadcaster.add_client("dummy_username", false);
+        broadcaster.broadcast("1", None).await;
+
+        let blocked = tokio::spawn({
+            let broadcaster = broadcaster.clone();
+            async move { broadcaster.broadcast("2", None).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+
+        assert_eq!(&*client.recv().await.data, "1");
+        assert_eq!(blocked.await.unwrap().delivered, 1);
+        assert_eq!(&*client.recv().await.data, "2");
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_blocking_on_a_full_queue() {
+        let shutdown = Shutdown::new();
+        let broadcaster = Broadcaster::new(
+            &SubscriberQueueSettings {
+                capacity: 1,
+                overflow: OverflowPolicy::Block,
+            },
+            shutdown.clone(),
+        );
+        let stalled = broadcaster.add_client("stalled", false);
+        broadcaster.broadcast("1", None).await;
+
+        let blocked = tokio::spawn({
+            let broadcaster = broadcaster.clone();
+            async move { broadcaster.broadcast("2", None).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+
+        shutdown.request();
+        let outcome = tokio::time::timeout(Duration::from_secs(1), blocked)
+            .await
+            .unwrap()
+            .unwrap();
+        // nobody got it, so the consumer requeues it
+        assert_eq!(outcome.delivered, 0);
+        assert_eq!(outcome.disconnected, 1);
+        tokio::time::timeout(Duration::from_secs(1), stalled.info.disconnect_requested())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn batches_are_limited_by_size_and_delay() {
+        let broadcaster = broadcaster(10, OverflowPolicy::DropOldest);
+        let client = broadcaster.add_client("dummy_username", false);
+        for event in ["1", "2", "3"] {
+            broadcaster.broadcast(event, None).await;
+        }
+
+        let data = |batch: Vec<QueuedEvent>| batch.into_iter().map(|e| e.data).collect::<Vec<_>>();
+        let batch = client.recv_batch(2, Duration::from_secs(60)).await;
+        assert_eq!(data(batch), vec![Arc::from("1"), Arc::from("2")]);
+        let batch = client.recv_batch(5, Duration::from_millis(10)).await;
+        assert_eq!(data(batch), vec![Arc::from("3")]);
+    }
+
+    #[tokio::test]
+    async fn confirmations_are_reported_once_every_client_answered() {
+        let broadcaster = broadcaster(10, OverflowPolicy::DropOldest);
+        let _sse = broadcaster.add_client("sse", false);
+        let first = broadcaster.add_client("first", true);
+        let second = broadcaster.add_client("second", true);
+        let (done, mut results) = tokio::sync::mpsc::unbounded_channel();
+        let target = |delivery_tag| {
+            Some(ConfirmTarget {
+                delivery_tag,
+                done: done.clone(),
+            })
+        };
+
+        let outcome = broadcaster.broadcast("1", target(1)).await;
+        assert_eq!(outcome.delivered, 3);
+        assert_eq!(outcome.awaiting_confirmation, 2);
+        let id = first.recv().await.id;
+        second.recv().await;
+        assert!(first.info.confirm(id));
+        assert!(!first.info.confirm(id));
+        assert!(results.try_recv().is_err());
+        assert!(second.info.confirm(id));
+        assert_eq!(
+            results.try_recv().unwrap(),
+            Confirmed {
+                delivery_tag: 1,
+                confirmed: true
+            }
+        );
+
+        // a client going away without confirming rejects the message
+        broadcaster.broadcast("2", target(2)).await;
+        let id = first.recv().await.id;
+        assert!(first.info.confirm(id));
+        drop(second);
+        assert_eq!(
+            results.try_recv().unwrap(),
+            Confirmed {
+                delivery_tag: 2,
+                confirmed: false
+            }
+        );
+
+        // so does not confirming it in time
+        broadcaster.broadcast("3", target(3)).await;
+        first.recv().await;
+        assert_eq!(first.info.expire_unconfirmed(Duration::from_secs(60)), 0);
+        assert_eq!(first.info.expire_unconfirmed(Duration::ZERO), 1);
+        assert_eq!(
+            results.try_recv().unwrap(),
+            Confirmed {
+                delivery_tag: 3,
+                confirmed: false
+            }
+        );
+
+        // nothing is reported if nobody has to confirm
+        drop(first);
+        broadcaster.broadcast("4", target(4)).await;
+        assert!(results.try_recv().is_err());
     }
 }
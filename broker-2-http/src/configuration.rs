@@ -1,15 +1,316 @@
 /// FOR DEVOPS USERS:
 /// 1) The root struct is "Settings", follow logic from there
 /// 2) integers can be provided as a string in config files or environment variables
-/// 3) if using environment variables, see comment in "get_configuration()" as an example of how nesting works
-/// 4) if using ONLY a file variable, this is determined from the APP_CONFIG_FILE environment variable (environment variables have higher precedence)
+/// 3) if using environment variables, see comment in "Cli::read_configuration()" as an example of how nesting works
+/// 4) the file is given with "--config" or the APP_CONFIG_FILE environment variable; environment variables have higher precedence, the command line ("--set ...") highest
 /// 5) Additional logic can be found in shared-deps/src/configuration.rs
 use secrecy::Secret;
-use serde_aux::field_attributes::deserialize_number_from_string;
+use serde_aux::field_attributes::{
+    deserialize_number_from_string, deserialize_option_number_from_string,
+};
 
-use intersect_ingress_proxy_common::configuration::{BrokerSettings, LogLevel};
+use crate::dead_letter::RejectionReason;
+use intersect_ingress_proxy_common::configuration::{
+    serialize_redacted, setting_path, BrokerSettings, ConfigProblems, LogLevel, Validate,
+};
+use intersect_ingress_proxy_common::protocols::amqp::is_routing_key_compliant;
+use intersect_ingress_proxy_common::reload::{compare_secret, Reload};
 
-#[derive(serde::Deserialize, Clone)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct AdminSettings {
+    /// username for Basic Authentication on the admin API
+    pub username: String,
+    /// password for Basic Authentication on the admin API
+    #[serde(serialize_with = "serialize_redacted")]
+    pub password: Secret<String>,
+}
+
+fn default_queue_name_template() -> String {
+    "broker-2-http.{topic_prefix}".to_owned()
+}
+
+fn default_binding_keys() -> Vec<String> {
+    vec![
+        "{topic_prefix}.#.lifecycle".to_owned(),
+        "{topic_prefix}.#.events".to_owned(),
+        // userspace messages are routed by their destination, which will be in another System for messages we need to forward
+        "#.userspace".to_owned(),
+    ]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// How the queue we consume from is declared on the broker.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct QueueSettings {
+    /// Name of the queue. "{topic_prefix}" and "{instance_id}" are substituted.
+    /// Names which are not valid AMQP queue names (i.e. too long) are hashed.
+    #[serde(default = "default_queue_name_template")]
+    pub name_template: String,
+    /// substituted for "{instance_id}" in the name template, required if the template uses it
+    pub instance_id: Option<String>,
+    /// routing key patterns the queue is bound to on the INTERSECT exchange, "{topic_prefix}" is substituted.
+    /// Messages which are not from our System are still filtered out after being consumed.
+    #[serde(default = "default_binding_keys")]
+    pub binding_keys: Vec<String>,
+    /// if true, the queue (and its messages) survive a broker restart
+    #[serde(default = "default_true")]
+    pub durable: bool,
+    /// if true, the queue is deleted once our connection closes
+    #[serde(default)]
+    pub exclusive: bool,
+    /// if true, the queue is deleted once our consumer is cancelled
+    #[serde(default)]
+    pub auto_delete: bool,
+    /// maximum number of messages in the queue, the oldest messages are dropped beyond this ("x-max-length")
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub max_length: Option<u32>,
+    /// messages older than this many milliseconds are dropped from the queue ("x-message-ttl")
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub message_ttl_ms: Option<u32>,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            name_template: default_queue_name_template(),
+            instance_id: None,
+            binding_keys: default_binding_keys(),
+            durable: true,
+            exclusive: false,
+            auto_delete: false,
+            max_length: None,
+            message_ttl_ms: None,
+        }
+    }
+}
+
+fn default_prefetch_count() -> u16 {
+    100
+}
+
+fn default_ack_batch_size() -> usize {
+    20
+}
+
+fn default_ack_interval_ms() -> u64 {
+    100
+}
+
+fn default_min_subscribers() -> usize {
+    1
+}
+
+/// How we consume messages from our queue.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct ConsumerSettings {
+    /// maximum number of unacknowledged messages the broker will send us at once
+    #[serde(
+        default = "default_prefetch_count",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub prefetch_count: u16,
+    /// acknowledge messages once this many are ready to be acknowledged.
+    /// Should be lower than "prefetch_count", or the broker will wait on the interval before sending more messages.
+    #[serde(
+        default = "default_ack_batch_size",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub ack_batch_size: usize,
+    /// acknowledge ready messages at least this often, regardless of the batch size
+    #[serde(
+        default = "default_ack_interval_ms",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub ack_interval_ms: u64,
+    /// only consume while at least this many SSE subscribers are connected, otherwise messages stay on the broker.
+    /// Set to the number of expected subscribers to wait for all of them, or 0 to always consume.
+    #[serde(
+        default = "default_min_subscribers",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub min_subscribers: usize,
+}
+
+impl Default for ConsumerSettings {
+    fn default() -> Self {
+        Self {
+            prefetch_count: default_prefetch_count(),
+            ack_batch_size: default_ack_batch_size(),
+            ack_interval_ms: default_ack_interval_ms(),
+            min_subscribers: default_min_subscribers(),
+        }
+    }
+}
+
+/// What to do with a message when no SSE subscriber is connected to receive it
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NoSubscriberPolicy {
+    /// put the message back on the queue, and stop consuming until a subscriber connects
+    Hold,
+    /// put the message back on the queue after "delay_ms", consumption continues in the meantime
+    Requeue,
+    /// move the message to a separate queue, where it waits "delay_ms" before being sent back to our queue
+    DelayQueue,
+}
+
+fn default_no_subscriber_policy() -> NoSubscriberPolicy {
+    NoSubscriberPolicy::Requeue
+}
+
+fn default_no_subscriber_delay_ms() -> u32 {
+    5000
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct NoSubscriberSettings {
+    #[serde(default = "default_no_subscriber_policy")]
+    pub policy: NoSubscriberPolicy,
+    /// how long a message waits before it is retried, for the "requeue" and "delay_queue" policies
+    #[serde(
+        default = "default_no_subscriber_delay_ms",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub delay_ms: u32,
+}
+
+impl Default for NoSubscriberSettings {
+    fn default() -> Self {
+        Self {
+            policy: default_no_subscriber_policy(),
+            delay_ms: default_no_subscriber_delay_ms(),
+        }
+    }
+}
+
+/// What to do when a message is broadcast to an SSE client whose queue is already full
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// disconnect the client, it can reconnect and start over
+    Disconnect,
+    /// drop the oldest message in the client's queue. The message is still acknowledged if other clients received it.
+    DropOldest,
+    /// wait for the client to catch up. This stops consumption from the broker for every client in the meantime.
+    Block,
+}
+
+fn default_overflow_policy() -> OverflowPolicy {
+    OverflowPolicy::DropOldest
+}
+
+fn default_subscriber_queue_capacity() -> usize {
+    256
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct SubscriberQueueSettings {
+    /// maximum number of messages waiting to be sent to a single SSE client
+    #[serde(
+        default = "default_subscriber_queue_capacity",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub capacity: usize,
+    #[serde(default = "default_overflow_policy")]
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for SubscriberQueueSettings {
+    fn default() -> Self {
+        Self {
+            capacity: default_subscriber_queue_capacity(),
+            overflow: default_overflow_policy(),
+        }
+    }
+}
+
+fn default_confirmation_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_shutdown_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_batch_max_messages() -> usize {
+    50
+}
+
+fn default_batch_max_delay_ms() -> u64 {
+    20
+}
+
+/// Batching several messages into one SSE event, for subscribers which ask for it
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct BatchSettings {
+    /// most messages sent in a single event
+    #[serde(
+        default = "default_batch_max_messages",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub max_messages: usize,
+    /// how long the first message of a batch waits for more to arrive
+    #[serde(
+        default = "default_batch_max_delay_ms",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub max_delay_ms: u64,
+}
+
+fn default_dead_letter_name() -> String {
+    "broker-2-http.dead-letters.{topic_prefix}".to_owned()
+}
+
+fn default_dead_letter_reasons() -> Vec<RejectionReason> {
+    // messages from other Systems are expected (they're for our own Services), so these are not dead-lettered by default
+    vec![RejectionReason::InvalidUtf8, RejectionReason::InvalidJson]
+}
+
+/// Where messages we refuse to forward are sent.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct DeadLetterSettings {
+    /// name of the (topic) exchange rejected messages are republished to, "{topic_prefix}" is substituted
+    #[serde(default = "default_dead_letter_name")]
+    pub exchange: String,
+    /// name of the durable queue bound to the exchange, "{topic_prefix}" is substituted
+    #[serde(default = "default_dead_letter_name")]
+    pub queue: String,
+    /// which rejections are dead-lettered: "invalid_utf8", "invalid_json", "foreign_source"
+    #[serde(default = "default_dead_letter_reasons")]
+    pub reasons: Vec<RejectionReason>,
+}
+
+/// Where a copy of every message we forward is kept, for debugging what crossed the bridge.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct RecordingSettings {
+    /// file forwarded messages are appended to as JSON lines, rotated files get ".1", ".2", ... appended (".1" is the newest)
+    pub path: String,
+    /// the file is rotated once it is at least this big
+    #[serde(
+        default = "default_recording_max_file_bytes",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub max_file_bytes: u64,
+    /// how many rotated files are kept besides the current one
+    #[serde(
+        default = "default_recording_max_files",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub max_files: usize,
+}
+
+fn default_recording_max_file_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_recording_max_files() -> usize {
+    5
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct Settings {
     /// configuration for the broker, which our applications are listening to
     pub broker: BrokerSettings, // TODO make this a Vec<BrokerSettings>
@@ -20,10 +321,234 @@ pub struct Settings {
     pub log_level: LogLevel,
     /// this should only contain the SYSTEM prefix, i.e. "organization.facility.system"
     pub topic_prefix: String,
+    /// queue declaration settings, each System sharing a broker must end up with a different queue name
+    #[serde(default)]
+    pub queue: QueueSettings,
     /// username for Basic Authentication
     pub username: String,
     /// password for Basic Authentication
+    #[serde(serialize_with = "serialize_redacted")]
     pub password: Secret<String>,
+    /// prefetch and acknowledgement batching
+    #[serde(default)]
+    pub consumer: ConsumerSettings,
+    /// what to do with messages when no subscriber is connected
+    #[serde(default)]
+    pub no_subscriber: NoSubscriberSettings,
+    /// per-subscriber queue size, and what to do when a subscriber falls behind
+    #[serde(default)]
+    pub subscriber_queue: SubscriberQueueSettings,
+    /// how long a subscriber which confirms messages (WebSocket, or SSE with "x-intersect-confirm") has to confirm one before it is requeued
+    #[serde(
+        default = "default_confirmation_timeout_ms",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub confirmation_timeout_ms: u64,
+    /// compress the SSE stream (gzip, brotli or zstd) for subscribers which send an "Accept-Encoding" header
+    #[serde(default = "default_true")]
+    pub compression: bool,
+    /// if set, subscribers which send the "x-intersect-accept-batch" header may get several messages per SSE event
+    pub batching: Option<BatchSettings>,
+    /// if set, messages we cannot forward are republished to a dead-letter exchange instead of being dropped
+    pub dead_letter: Option<DeadLetterSettings>,
+    /// if set, every message we forward is also recorded to a local file (replay it with "http-2-broker-replay")
+    pub recording: Option<RecordingSettings>,
+    /// credentials for the "/admin" API, which is disabled if this is not provided.
+    /// These should differ from the credentials subscribers use.
+    pub admin: Option<AdminSettings>,
+    /// on shutdown, how long subscribers get to receive and confirm the messages they were already sent, before they are requeued
+    #[serde(
+        default = "default_shutdown_timeout_ms",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub shutdown_timeout_ms: u64,
     /// set to true for developer-unfriendly settings (currently just log formats)
     pub production: bool,
+    /// full URL of an OpenTelemetry collector's OTLP/HTTP traces endpoint (i.e. "http://localhost:4318/v1/traces").
+    /// If not set, spans are not exported.
+    pub otlp_endpoint: Option<String>,
+}
+
+/// a System's topic prefix is one or more dot-separated words, without wildcards
+fn is_valid_topic_prefix(topic_prefix: &str) -> bool {
+    topic_prefix
+        .split('.')
+        .all(|word| !word.is_empty() && !word.contains(['*', '#']))
+}
+
+impl Validate for QueueSettings {
+    fn validate(&self, path: &str, problems: &mut ConfigProblems) {
+        let name_template = setting_path(path, "name_template");
+        problems.check_not_empty(&name_template, &self.name_template);
+        if self.name_template.contains("{instance_id}") && self.instance_id.is_none() {
+            problems.add(
+                &name_template,
+                format!(
+                    "uses {{instance_id}}, but {} is not set",
+                    setting_path(path, "instance_id")
+                ),
+            );
+        }
+        let binding_keys = setting_path(path, "binding_keys");
+        if self.binding_keys.is_empty() {
+            problems.add(
+                &binding_keys,
+                "must not be empty, we would never receive any messages",
+            );
+        }
+        for key in &self.binding_keys {
+            if key.is_empty() || !is_routing_key_compliant(key) {
+                problems.add(
+                    &binding_keys,
+                    format!("{:?} is not a valid AMQP routing key pattern", key),
+                );
+            }
+        }
+    }
+}
+
+impl Validate for Settings {
+    fn validate(&self, path: &str, problems: &mut ConfigProblems) {
+        self.broker
+            .validate(&setting_path(path, "broker"), problems);
+        problems.check_port(&setting_path(path, "app_port"), self.app_port);
+        let topic_prefix = setting_path(path, "topic_prefix");
+        problems.check_not_empty(&topic_prefix, &self.topic_prefix);
+        if !self.topic_prefix.is_empty() && !is_valid_topic_prefix(&self.topic_prefix) {
+            problems.add(
+                &topic_prefix,
+                "must be dot-separated words without wildcards, i.e. \"organization.facility.system\"",
+            );
+        }
+        self.queue.validate(&setting_path(path, "queue"), problems);
+        problems.check_not_empty(&setting_path(path, "username"), &self.username);
+        problems.check_positive(
+            &setting_path(path, "consumer.prefetch_count"),
+            self.consumer.prefetch_count.into(),
+        );
+        problems.check_positive(
+            &setting_path(path, "consumer.ack_batch_size"),
+            self.consumer.ack_batch_size as u64,
+        );
+        problems.check_positive(
+            &setting_path(path, "subscriber_queue.capacity"),
+            self.subscriber_queue.capacity as u64,
+        );
+        problems.check_positive(
+            &setting_path(path, "confirmation_timeout_ms"),
+            self.confirmation_timeout_ms,
+        );
+        if let Some(batching) = &self.batching {
+            problems.check_positive(
+                &setting_path(path, "batching.max_messages"),
+                batching.max_messages as u64,
+            );
+        }
+        if let Some(dead_letter) = &self.dead_letter {
+            problems.check_not_empty(
+                &setting_path(path, "dead_letter.exchange"),
+                &dead_letter.exchange,
+            );
+            problems.check_not_empty(&setting_path(path, "dead_letter.queue"), &dead_letter.queue);
+        }
+        if let Some(recording) = &self.recording {
+            problems.check_not_empty(&setting_path(path, "recording.path"), &recording.path);
+            problems.check_positive(
+                &setting_path(path, "recording.max_file_bytes"),
+                recording.max_file_bytes,
+            );
+        }
+        if let Some(admin) = &self.admin {
+            problems.check_not_empty(&setting_path(path, "admin.username"), &admin.username);
+        }
+        if let Some(otlp_endpoint) = &self.otlp_endpoint {
+            problems.check_url(
+                &setting_path(path, "otlp_endpoint"),
+                otlp_endpoint,
+                &["http", "https"],
+            );
+        }
+    }
+}
+
+impl Reload for Settings {
+    /// Subscribers keep the batching they connected with, everything else takes effect immediately.
+    /// Enabling or disabling the admin API needs a restart.
+    const RELOADABLE: &'static [&'static str] = &[
+        "log_level",
+        "username",
+        "password",
+        "admin.username",
+        "admin.password",
+        "queue.binding_keys",
+        "batching",
+        "confirmation_timeout_ms",
+    ];
+
+    fn changed_secrets(&self, new: &Self, path: &str, changed: &mut Vec<String>) {
+        compare_secret(
+            &setting_path(path, "broker.password"),
+            &self.broker.password,
+            &new.broker.password,
+            changed,
+        );
+        compare_secret(
+            &setting_path(path, "password"),
+            &self.password,
+            &new.password,
+            changed,
+        );
+        if let (Some(admin), Some(new_admin)) = (&self.admin, &new.admin) {
+            compare_secret(
+                &setting_path(path, "admin.password"),
+                &admin.password,
+                &new_admin.password,
+                changed,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(yaml: &str) -> Settings {
+        config::Config::builder()
+            .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    #[test]
+    fn every_invalid_setting_is_reported() {
+        let settings = settings(
+            r#"
+            app_port: 0
+            broker: { username: user, password: pass, host: localhost, port: 5672 }
+            topic_prefix: "organization.*.system"
+            log_level: info
+            username: user
+            password: pass
+            production: false
+            queue: { name_template: "proxy.{instance_id}", binding_keys: [] }
+            otlp_endpoint: "localhost:4318"
+            "#,
+        );
+        let mut problems = ConfigProblems::new();
+        settings.validate("", &mut problems);
+        let paths: Vec<&str> = problems.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "app_port",
+                "topic_prefix",
+                "queue.name_template",
+                "queue.binding_keys",
+                "otlp_endpoint"
+            ]
+        );
+    }
 }
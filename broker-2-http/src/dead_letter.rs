@@ -0,0 +1,197 @@
+/// Messages we cannot forward (i.e. they are not valid INTERSECT messages) are republished to a dead-letter exchange,
+/// along with why we rejected them and where they originally came from. They can then be inspected and requeued
+/// with the "broker-2-http-dead-letters" tool.
+use std::time::Duration;
+
+use amqprs::{
+    channel::{Channel, ExchangeDeclareArguments, QueueBindArguments, QueueDeclareArguments},
+    connection::Connection,
+    BasicProperties, FieldTable, FieldValue,
+};
+
+use crate::configuration::DeadLetterSettings;
+use intersect_ingress_proxy_common::protocols::amqp::compliant_queue_name;
+use intersect_ingress_proxy_common::protocols::publish_confirm::publish_confirmed;
+
+/// how long we wait for the broker to confirm a message we moved to or from the dead-letter queue
+pub const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// header containing the RejectionReason
+pub const REASON_HEADER: &str = "x-proxy-rejection-reason";
+/// header containing the exchange the message was originally published to
+pub const ORIGINAL_EXCHANGE_HEADER: &str = "x-original-exchange";
+/// header containing the routing key the message was originally published with
+pub const ORIGINAL_ROUTING_KEY_HEADER: &str = "x-original-routing-key";
+
+/// Why the proxy refused to forward a message
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    /// message is not UTF-8, so it can't be sent over SSE
+    InvalidUtf8,
+    /// message is UTF-8, but not an INTERSECT message
+    InvalidJson,
+    /// message is from another System, so it's not ours to forward
+    ForeignSource,
+}
+
+impl RejectionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectionReason::InvalidUtf8 => "invalid_utf8",
+            RejectionReason::InvalidJson => "invalid_json",
+            RejectionReason::ForeignSource => "foreign_source",
+        }
+    }
+}
+
+/// The dead-letter exchange and queue, with their names resolved from the configuration
+pub struct DeadLetterTarget {
+    pub exchange: String,
+    pub queue: String,
+    reasons: Vec<RejectionReason>,
+}
+
+impl DeadLetterTarget {
+    /// "{topic_prefix}" is substituted in the exchange and queue names
+    pub fn new(settings: &DeadLetterSettings, config_topic: &str) -> Self {
+        Self {
+            exchange: compliant_queue_name(
+                &settings.exchange.replace("{topic_prefix}", config_topic),
+            ),
+            queue: compliant_queue_name(&settings.queue.replace("{topic_prefix}", config_topic)),
+            reasons: settings.reasons.clone(),
+        }
+    }
+
+    /// whether messages rejected for this reason should be dead-lettered (otherwise they are just dropped)
+    pub fn handles(&self, reason: RejectionReason) -> bool {
+        self.reasons.contains(&reason)
+    }
+
+    /// Declare the exchange and a durable queue which receives everything published to it
+    pub async fn declare(&self, channel: &Channel) -> Result<(), amqprs::error::Error> {
+        channel
+            .exchange_declare(
+                ExchangeDeclareArguments::new(&self.exchange, "topic")
+                    .durable(true)
+                    .finish(),
+            )
+            .await?;
+        channel
+            .queue_declare(QueueDeclareArguments::durable_client_named(&self.queue))
+            .await?;
+        channel
+            .queue_bind(QueueBindArguments::new(&self.queue, &self.exchange, "#"))
+            .await
+    }
+
+    /// Republish a rejected message to the dead-letter exchange, keeping its routing key, properties and content.
+    /// Only returns Ok once the broker has confirmed the dead-lettered copy, so the original can be acknowledged.
+    pub async fn publish(
+        &self,
+        connection: &Connection,
+        reason: RejectionReason,
+        original_exchange: &str,
+        original_routing_key: &str,
+        properties: Option<BasicProperties>,
+        content: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let mut properties = properties.unwrap_or_default();
+        let headers = dead_letter_headers(
+            properties.headers(),
+            reason,
+            original_exchange,
+            original_routing_key,
+        );
+        properties.with_headers(headers);
+        let confirmed = publish_confirmed(
+            connection,
+            properties,
+            content,
+            &self.exchange,
+            original_routing_key,
+            CONFIRM_TIMEOUT,
+        )
+        .await?;
+        if !confirmed {
+            anyhow::bail!("the broker did not confirm the dead-lettered message");
+        }
+        Ok(())
+    }
+}
+
+/// the message's existing headers, plus our own
+pub fn dead_letter_headers(
+    existing: Option<&FieldTable>,
+    reason: RejectionReason,
+    original_exchange: &str,
+    original_routing_key: &str,
+) -> FieldTable {
+    let mut headers = existing.cloned().unwrap_or_default();
+    for (key, value) in [
+        (REASON_HEADER, reason.as_str()),
+        (ORIGINAL_EXCHANGE_HEADER, original_exchange),
+        (ORIGINAL_ROUTING_KEY_HEADER, original_routing_key),
+    ] {
+        let key = key.try_into().unwrap();
+        // FieldTable miscalculates its size if an existing key is overwritten, so remove it first
+        headers.remove(&key);
+        // routing keys and exchange names are always short enough for a header value
+        if let Ok(value) = value.try_into() {
+            headers.insert(key, FieldValue::S(value));
+        }
+    }
+    headers
+}
+
+/// The original headers of a dead-lettered message, without the ones we added
+pub fn strip_dead_letter_headers(headers: &FieldTable) -> FieldTable {
+    let mut stripped = headers.clone();
+    for key in [
+        REASON_HEADER,
+        ORIGINAL_EXCHANGE_HEADER,
+        ORIGINAL_ROUTING_KEY_HEADER,
+    ] {
+        stripped.remove(&key.try_into().unwrap());
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use intersect_ingress_proxy_common::protocols::amqp::string_headers;
+
+    #[test]
+    fn dead_letter_headers_can_be_stripped() {
+        let mut original = FieldTable::new();
+        original.insert(
+            "traceparent".try_into().unwrap(),
+            FieldValue::S(
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                    .try_into()
+                    .unwrap(),
+            ),
+        );
+
+        let headers = dead_letter_headers(
+            Some(&original),
+            RejectionReason::InvalidJson,
+            "intersect-messages",
+            "organization.facility.system.subsystem.service.events",
+        );
+        let strings = string_headers(Some(&headers));
+        assert_eq!(strings[REASON_HEADER], "invalid_json");
+        assert_eq!(strings[ORIGINAL_EXCHANGE_HEADER], "intersect-messages");
+        assert_eq!(
+            strings[ORIGINAL_ROUTING_KEY_HEADER],
+            "organization.facility.system.subsystem.service.events"
+        );
+
+        assert_eq!(
+            string_headers(Some(&strip_dead_letter_headers(&headers))),
+            string_headers(Some(&original))
+        );
+    }
+}
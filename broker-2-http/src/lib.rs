@@ -1,5 +1,11 @@
+pub mod acker;
 pub mod amqp_consumer;
 pub mod broadcaster;
 pub mod configuration;
+pub mod dead_letter;
+pub mod metrics;
+pub mod recorder;
 pub mod routes;
+pub mod tap;
+pub mod undelivered;
 pub mod webapp;
@@ -1,43 +1,124 @@
+use std::time::Duration;
+
 use broker_2_http::{
-    amqp_consumer::broker_consumer_loop, broadcaster::Broadcaster, configuration::Settings,
+    amqp_consumer::{broker_consumer_loop, ConsumerControl, ConsumerTopology},
+    broadcaster::Broadcaster,
+    configuration::Settings,
     webapp::WebApplication,
 };
 
-use intersect_ingress_proxy_common::configuration::get_configuration;
+use intersect_ingress_proxy_common::cli::Cli;
+use intersect_ingress_proxy_common::configuration::configuration_or_exit;
+use intersect_ingress_proxy_common::health::{
+    HealthState, BROKER_COMPONENT, DEFAULT_MAX_HEARTBEAT_AGE,
+};
+use intersect_ingress_proxy_common::protocols::amqp::SharedConnection;
+use intersect_ingress_proxy_common::reload::reload_on_change;
+use intersect_ingress_proxy_common::signals::Shutdown;
 use intersect_ingress_proxy_common::telemetry::{
-    get_json_subscriber, get_pretty_subscriber, init_subscriber,
+    get_json_subscriber, get_pretty_subscriber, init_subscriber, OtlpTracing,
 };
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let configuration = get_configuration::<Settings>().expect("Failed to read configuration");
+    let cli = Cli::parse_for(
+        "broker-2-http",
+        env!("CARGO_PKG_VERSION"),
+        include_str!("../conf.yaml"),
+    );
+    let configuration = configuration_or_exit(&cli, Cli::read_configuration::<Settings>);
+
+    let otlp = configuration.otlp_endpoint.as_ref().map(|endpoint| {
+        OtlpTracing::init("broker-2-http", endpoint).expect("Failed to configure OTLP exporter")
+    });
 
     // Start logging
-    if configuration.production {
-        let subscriber = get_json_subscriber(
+    let log_level = if configuration.production {
+        let (subscriber, log_level) = get_json_subscriber(
             "broker-2-http".into(),
             configuration.log_level.to_string(),
             std::io::stderr,
+            otlp.as_ref(),
         );
         init_subscriber(subscriber);
+        log_level
     } else {
-        let subscriber = get_pretty_subscriber(configuration.log_level.to_string());
+        let (subscriber, log_level) =
+            get_pretty_subscriber(configuration.log_level.to_string(), otlp.as_ref());
         init_subscriber(subscriber);
-    }
+        log_level
+    };
+    let topology = ConsumerTopology::new(&configuration)?;
 
-    let broadcaster = Broadcaster::new();
+    let shutdown = Shutdown::new();
+    shutdown.request_on_os_signal();
 
-    let application = WebApplication::build(&configuration, broadcaster.clone()).await?;
+    let broadcaster = Broadcaster::new(&configuration.subscriber_queue, shutdown.clone());
+    let health = HealthState::new(&[BROKER_COMPONENT], DEFAULT_MAX_HEARTBEAT_AGE);
 
-    let broker_join_handle = broker_consumer_loop(
-        configuration.broker.clone(),
-        configuration.topic_prefix.clone(),
+    let consumer_control = ConsumerControl::new();
+
+    let application = WebApplication::build(
+        &configuration,
         broadcaster.clone(),
+        health.clone(),
+        consumer_control.clone(),
+        log_level.clone(),
+        shutdown.clone(),
     )
-    .await;
-    application.run_until_stopped().await?;
-    tracing::warn!("Application shutting down, please wait for cleanups...");
-    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
-    broker_join_handle.abort();
+    .await?;
+
+    // SIGHUP / SIGUSR1 or changing the configuration file applies credentials, log level and bindings again
+    let app_state = application.state();
+    let binding_keys = topology.binding_keys();
+    tokio::spawn(reload_on_change(
+        cli,
+        configuration.clone(),
+        Cli::read_configuration::<Settings>,
+        move |new: &Settings, changed: &[String]| {
+            app_state.reload(new);
+            binding_keys.reload(new);
+            // the admin API may have changed the log level since, leave it unless the configured one changed
+            if changed.iter().any(|path| path == "log_level") {
+                if let Err(e) = log_level.set_level(&new.log_level) {
+                    tracing::error!(error = ?e, "could not change log level");
+                }
+            }
+        },
+    ));
+    let server_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        if let Err(e) = application.run_until_stopped().await {
+            tracing::error!(error = ?e, "web server failed");
+            server_shutdown.request();
+        }
+    });
+
+    let connection = SharedConnection::new(configuration.broker.clone());
+    broker_consumer_loop(
+        connection.clone(),
+        configuration.topic_prefix.clone(),
+        topology,
+        broadcaster.clone(),
+        health,
+        consumer_control,
+        shutdown.clone(),
+    );
+
+    // subscribers drain, then the consumer acknowledges what they confirmed and closes its channel
+    let abandoned = shutdown
+        .wait(Duration::from_millis(configuration.shutdown_timeout_ms))
+        .await;
+    if abandoned > 0 {
+        tracing::error!(
+            "{} tasks did not shut down in time, whatever they had not finished was abandoned",
+            abandoned
+        );
+    }
+    connection.close().await;
+    if let Some(otlp) = otlp {
+        otlp.shutdown();
+    }
+    tracing::info!("process gracefully shutdown");
     Ok(())
 }
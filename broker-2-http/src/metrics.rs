@@ -0,0 +1,178 @@
+/// Prometheus metrics specific to broker-2-http. These are exposed on the "/metrics" endpoint.
+use std::sync::LazyLock;
+
+use prometheus::{
+    register_int_counter, register_int_counter_vec, register_int_gauge, IntCounter, IntCounterVec,
+    IntGauge,
+};
+
+/// every message we received from the broker, regardless of what we did with it
+pub static MESSAGES_CONSUMED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_messages_consumed_total",
+        "Number of messages consumed from the broker"
+    )
+    .unwrap()
+});
+
+/// messages which were handed off to at least one SSE client
+pub static MESSAGES_BROADCAST: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_messages_broadcast_total",
+        "Number of messages broadcast to at least one SSE client"
+    )
+    .unwrap()
+});
+
+pub static MESSAGES_ACKED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_messages_acked_total",
+        "Number of messages acknowledged on the broker"
+    )
+    .unwrap()
+});
+
+pub static MESSAGES_UNACKED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_messages_unacked_total",
+        "Number of messages we did not acknowledge on the broker"
+    )
+    .unwrap()
+});
+
+/// messages nobody received which were put back on the queue, labeled by the no-subscriber policy ("hold", "requeue", "delay_queue"),
+/// "unconfirmed" for messages a WebSocket subscriber did not confirm, or "dead_letter" for rejected messages we could not dead-letter
+pub static MESSAGES_REQUEUED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "broker2http_messages_requeued_total",
+        "Number of messages requeued because no subscriber received or confirmed them",
+        &["policy"]
+    )
+    .unwrap()
+});
+
+/// number of basic.ack frames sent, each of which may acknowledge multiple messages
+pub static ACK_BATCHES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_ack_batches_total",
+        "Number of acknowledgements sent to the broker, each may cover multiple messages"
+    )
+    .unwrap()
+});
+
+/// labeled by the reason the message was not allowed through ("foreign_source", "invalid_json", "invalid_utf8")
+pub static PASSTHROUGH_REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "broker2http_passthrough_rejections_total",
+        "Number of messages the passthrough filter refused to broadcast",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// labeled by the same reasons as PASSTHROUGH_REJECTIONS
+pub static DEAD_LETTERED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "broker2http_messages_dead_lettered_total",
+        "Number of rejected messages republished to the dead-letter exchange",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// how many times a message found an SSE client's queue full
+pub static LAGGED_RECEIVERS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_lagged_receivers_total",
+        "Number of times an SSE client's queue was full when a message was broadcast"
+    )
+    .unwrap()
+});
+
+/// how many messages SSE clients missed because the "drop_oldest" overflow policy dropped them from a full queue
+pub static LAGGED_MESSAGES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_lagged_messages_total",
+        "Number of messages SSE clients missed from lagging behind the broadcaster"
+    )
+    .unwrap()
+});
+
+/// SSE clients disconnected by the "disconnect" overflow policy
+pub static SLOW_CLIENTS_DISCONNECTED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_slow_clients_disconnected_total",
+        "Number of SSE clients disconnected because their queue was full"
+    )
+    .unwrap()
+});
+
+/// messages a subscriber did not confirm within the confirmation timeout, these are requeued
+pub static CONFIRMATION_TIMEOUTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_confirmation_timeouts_total",
+        "Number of messages requeued because a subscriber did not confirm them in time"
+    )
+    .unwrap()
+});
+
+pub static SSE_CLIENTS_CONNECTED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "broker2http_sse_clients_connected",
+        "Number of SSE clients currently connected"
+    )
+    .unwrap()
+});
+
+/// operators connected to "/admin/tap", these aren't counted as SSE clients
+pub static TAP_SUBSCRIBERS_CONNECTED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "broker2http_tap_subscribers_connected",
+        "Number of operators currently tapping consumed messages"
+    )
+    .unwrap()
+});
+
+pub static WS_CLIENTS_CONNECTED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "broker2http_ws_clients_connected",
+        "Number of WebSocket clients currently connected"
+    )
+    .unwrap()
+});
+
+/// size of the event source data we broadcast (counted once per message, not once per client)
+pub static BYTES_BROADCAST: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_bytes_broadcast_total",
+        "Number of event source data bytes broadcast to SSE clients"
+    )
+    .unwrap()
+});
+
+/// SSE events carrying more than one message, for subscribers which accept batches
+pub static BATCHES_SENT: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_batches_sent_total",
+        "Number of SSE events sent which contained a batch of messages"
+    )
+    .unwrap()
+});
+
+/// forwarded messages written to the recording, if recording is configured
+pub static MESSAGES_RECORDED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_messages_recorded_total",
+        "Number of forwarded messages written to the recording"
+    )
+    .unwrap()
+});
+
+/// forwarded messages missing from the recording, because the recorder fell behind or could not write them
+pub static RECORDING_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broker2http_recording_failures_total",
+        "Number of forwarded messages which could not be recorded"
+    )
+    .unwrap()
+});
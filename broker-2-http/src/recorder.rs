@@ -0,0 +1,164 @@
+/// Recording what we forward to a rotating local file, see "RecordingSettings".
+/// Writing happens on its own thread, so a slow disk never holds up forwarding: if the recorder falls behind, messages are left out of the recording.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+};
+
+use intersect_ingress_proxy_common::recording::RecordedEvent;
+
+use crate::{
+    configuration::RecordingSettings,
+    metrics::{MESSAGES_RECORDED, RECORDING_FAILURES},
+};
+
+/// how many messages may wait to be written before we start leaving them out
+const RECORDING_BACKLOG: usize = 10_000;
+
+/// Hands forwarded messages to the thread writing the recording, which stops once every Recorder is dropped.
+#[derive(Clone)]
+pub struct Recorder {
+    events: SyncSender<RecordedEvent>,
+}
+
+impl Recorder {
+    /// Open (or create) the recording and start writing to it. Fails if the file can't be opened.
+    pub fn start(settings: &RecordingSettings) -> io::Result<Self> {
+        let file = RotatingFile::open(settings)?;
+        let (events, received) = sync_channel(RECORDING_BACKLOG);
+        std::thread::Builder::new()
+            .name("recorder".into())
+            .spawn(move || write_events(file, received))?;
+        Ok(Self { events })
+    }
+
+    /// Record a message we forwarded
+    pub fn record(&self, routing_key: &str, envelope: &str) {
+        let event = match RecordedEvent::new(routing_key, envelope) {
+            Ok(event) => event,
+            Err(e) => {
+                // we only forward INTERSECT JSON, so this shouldn't happen
+                RECORDING_FAILURES.inc();
+                tracing::warn!(error = ?e, "could not record message, it is not JSON");
+                return;
+            }
+        };
+        match self.events.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                RECORDING_FAILURES.inc();
+                tracing::warn!("recorder is falling behind, message left out of the recording");
+            }
+            Err(TrySendError::Disconnected(_)) => RECORDING_FAILURES.inc(),
+        }
+    }
+}
+
+fn write_events(mut file: RotatingFile, events: Receiver<RecordedEvent>) {
+    for event in events {
+        // serializing a recorded event can't fail
+        let mut line = serde_json::to_vec(&event).unwrap();
+        line.push(b'\n');
+        match file.write_line(&line) {
+            Ok(()) => MESSAGES_RECORDED.inc(),
+            Err(e) => {
+                RECORDING_FAILURES.inc();
+                tracing::error!(error = ?e, "could not write to recording {}", file.path.display());
+            }
+        }
+    }
+}
+
+/// The recording file, which is moved aside once it grows too big
+struct RotatingFile {
+    path: PathBuf,
+    max_file_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(settings: &RecordingSettings) -> io::Result<Self> {
+        let path = PathBuf::from(&settings.path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_file_bytes: settings.max_file_bytes,
+            max_files: settings.max_files,
+            file,
+            size,
+        })
+    }
+
+    /// Write a whole line, rotating afterwards if the file is now too big
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        if self.size >= self.max_file_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// "path" becomes "path.1", "path.1" becomes "path.2" and so on, dropping the oldest
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    std::fs::rename(&from, rotated_path(&self.path, index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recording_is_rotated_once_it_is_too_big() {
+        let directory = std::env::temp_dir().join(format!("recording-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("recording.jsonl");
+        let mut file = RotatingFile::open(&RecordingSettings {
+            path: path.to_string_lossy().into_owned(),
+            max_file_bytes: 10,
+            max_files: 2,
+        })
+        .unwrap();
+        for line in ["first line\n", "second line\n", "third line\n"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+        file.write_line(b"fourth\n").unwrap();
+
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fourth\n");
+        assert_eq!(read(rotated_path(&path, 1)), "third line\n");
+        assert_eq!(read(rotated_path(&path, 2)), "second line\n");
+        // only two rotated files are kept
+        assert!(!rotated_path(&path, 3).exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    middleware::{from_fn_with_state, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, post},
+    Json, Router,
+};
+use axum_extra::{
+    headers::{authorization::Basic, Authorization},
+    TypedHeader,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
+    time::UNIX_EPOCH,
+};
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::metrics::TAP_SUBSCRIBERS_CONNECTED;
+use crate::routes::auth::credentials_match;
+use crate::routes::subscribe::ConnectedClientGuard;
+use crate::tap::{TapDecision, TapSample};
+use crate::webapp::WebApplicationState;
+use intersect_ingress_proxy_common::{configuration::LogLevel, health::HealthReport};
+
+#[derive(Serialize)]
+pub struct SubscriberSummary {
+    pub id: Uuid,
+    /// the Basic Auth username the subscriber connected with
+    pub identity: String,
+    /// unix time in milliseconds
+    pub connected_at_ms: u64,
+    pub messages_sent: u64,
+    pub messages_lagged: u64,
+    pub backlog: u64,
+    /// whether the subscriber confirms every message
+    pub confirms: bool,
+    /// messages the subscriber was sent but has not confirmed yet
+    pub unconfirmed: usize,
+}
+
+#[derive(Serialize)]
+pub struct ConsumerStatus {
+    pub paused: bool,
+    /// whether we currently have a consumer on the queue, false while paused, disconnected or waiting for subscribers
+    pub consuming: bool,
+}
+
+impl ConsumerStatus {
+    fn new(app_state: &WebApplicationState) -> Self {
+        Self {
+            paused: app_state.consumer_control.is_paused(),
+            consuming: app_state.consumer_control.is_consuming(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BrokerStatus {
+    pub consumer: ConsumerStatus,
+    pub connection: HealthReport,
+}
+
+#[derive(Serialize)]
+pub struct LogLevelStatus {
+    /// the active filter directives
+    pub filter: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LogLevelChange {
+    /// one of "trace", "debug", "info", "warn"/"warning", "error"
+    pub level: String,
+}
+
+#[derive(Deserialize)]
+pub struct TapQuery {
+    /// only send every n-th message (of those matching "decisions"), default 1
+    pub every: Option<u64>,
+    /// comma-separated decisions to send, i.e. "invalid_json,invalid_utf8", default all
+    pub decisions: Option<String>,
+}
+
+/// All admin routes, these require the separate admin credentials.
+pub fn admin_router(app_state: Arc<WebApplicationState>) -> Router<Arc<WebApplicationState>> {
+    Router::new()
+        .route("/subscribers", get(list_subscribers))
+        .route("/subscribers/:id", delete(disconnect_subscriber))
+        .route("/consumer/pause", post(pause_consumer))
+        .route("/consumer/resume", post(resume_consumer))
+        .route("/broker", get(broker_status))
+        .route("/log-level", get(get_log_level).put(set_log_level))
+        .route("/tap", get(tap_messages))
+        .route_layer(from_fn_with_state(app_state, admin_auth))
+}
+
+async fn admin_auth(
+    State(app_state): State<Arc<WebApplicationState>>,
+    authorization: Option<TypedHeader<Authorization<Basic>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = match (&app_state.live().admin, authorization) {
+        (Some(admin), Some(TypedHeader(authorization))) => {
+            credentials_match(&authorization, &admin.username, &admin.password)
+        }
+        _ => false,
+    };
+    if !authorized {
+        return (StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+    }
+    next.run(request).await
+}
+
+/// List every connected SSE subscriber
+async fn list_subscribers(
+    State(app_state): State<Arc<WebApplicationState>>,
+) -> Json<Vec<SubscriberSummary>> {
+    let subscribers = app_state
+        .broadcaster
+        .clients()
+        .iter()
+        .map(|client| SubscriberSummary {
+            id: client.id,
+            identity: client.identity.clone(),
+            connected_at_ms: client
+                .connected_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            messages_sent: client.messages_sent.load(Ordering::Relaxed),
+            messages_lagged: client.messages_lagged.load(Ordering::Relaxed),
+            backlog: client.backlog.load(Ordering::Relaxed),
+            confirms: client.confirms,
+            unconfirmed: client.unconfirmed_count(),
+        })
+        .collect();
+    Json(subscribers)
+}
+
+/// Forcibly disconnect an SSE subscriber. Note that nothing stops the subscriber from reconnecting.
+async fn disconnect_subscriber(
+    State(app_state): State<Arc<WebApplicationState>>,
+    Path(id): Path<Uuid>,
+) -> impl IntoResponse {
+    if app_state.broadcaster.disconnect_client(&id) {
+        tracing::warn!("admin requested disconnect of SSE subscriber {}", id);
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "no such subscriber").into_response()
+    }
+}
+
+/// Stop consuming from the broker, messages will accumulate on the broker until consumption is resumed
+async fn pause_consumer(State(app_state): State<Arc<WebApplicationState>>) -> Json<ConsumerStatus> {
+    tracing::warn!("admin paused consumption from the broker");
+    app_state.consumer_control.pause();
+    Json(ConsumerStatus::new(&app_state))
+}
+
+async fn resume_consumer(
+    State(app_state): State<Arc<WebApplicationState>>,
+) -> Json<ConsumerStatus> {
+    tracing::warn!("admin resumed consumption from the broker");
+    app_state.consumer_control.resume();
+    Json(ConsumerStatus::new(&app_state))
+}
+
+async fn broker_status(State(app_state): State<Arc<WebApplicationState>>) -> Json<BrokerStatus> {
+    Json(BrokerStatus {
+        consumer: ConsumerStatus::new(&app_state),
+        connection: app_state.health.readiness_report(),
+    })
+}
+
+async fn get_log_level(State(app_state): State<Arc<WebApplicationState>>) -> Json<LogLevelStatus> {
+    Json(LogLevelStatus {
+        filter: app_state.log_level.current(),
+    })
+}
+
+/// Change the log level until the next change or restart. Reloading a configuration with a different "log_level" also changes it.
+async fn set_log_level(
+    State(app_state): State<Arc<WebApplicationState>>,
+    Json(change): Json<LogLevelChange>,
+) -> Response {
+    let Ok(level) = LogLevel::from_str(&change.level) else {
+        return (StatusCode::UNPROCESSABLE_ENTITY, "unknown log level").into_response();
+    };
+    if let Err(e) = app_state.log_level.set_level(&level) {
+        tracing::error!(error = ?e, "could not change log level");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not change log level",
+        )
+            .into_response();
+    }
+    Json(LogLevelStatus {
+        filter: app_state.log_level.current(),
+    })
+    .into_response()
+}
+
+/// Stream a sample of the messages we consume, with what we decided to do with them, as SSE.
+/// Tapping never affects delivery: a tap is not a subscriber, and it misses messages if it falls behind.
+async fn tap_messages(
+    State(app_state): State<Arc<WebApplicationState>>,
+    Query(query): Query<TapQuery>,
+) -> Response {
+    let decisions: Result<Vec<TapDecision>, String> = query
+        .decisions
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .filter(|decision| !decision.is_empty())
+        .map(TapDecision::from_str)
+        .collect();
+    let decisions = match decisions {
+        Ok(decisions) => decisions,
+        Err(e) => return (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+    };
+    let mut sample = TapSample::new(query.every.unwrap_or(1), decisions);
+    let mut events = app_state.broadcaster.tap().subscribe();
+    tracing::warn!("admin started tapping consumed messages");
+
+    let stream = async_stream::stream! {
+        let _guard = ConnectedClientGuard::new(&TAP_SUBSCRIBERS_CONNECTED);
+        loop {
+            tokio::select! {
+                _ = app_state.shutdown.requested() => break,
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        if sample.wants(&event) {
+                            // serializing a tap event can't fail
+                            yield Ok::<_, Infallible>(Event::default().data(serde_json::to_string(&*event).unwrap()));
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::debug!("tap fell behind, it missed {} messages", missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    };
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
@@ -0,0 +1,11 @@
+use axum_extra::headers::{authorization::Basic, Authorization};
+use secrecy::{ExposeSecret, Secret};
+
+/// check Basic Authentication credentials against the ones we've configured
+pub fn credentials_match(
+    authorization: &Authorization<Basic>,
+    username: &str,
+    password: &Secret<String>,
+) -> bool {
+    authorization.username() == username && authorization.password() == password.expose_secret()
+}
@@ -1,6 +1,8 @@
 use axum::http::StatusCode;
 
 /// Return `200 OK` if the API is running and is accessible
+///
+/// This does not reflect broker state, Kubernetes probes should use "/livez" and "/readyz" instead.
 pub async fn health_check() -> StatusCode {
     StatusCode::OK
 }
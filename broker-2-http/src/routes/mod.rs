@@ -1,3 +1,6 @@
+pub mod admin;
+pub mod auth;
 pub mod health_check;
 pub mod not_found;
 pub mod subscribe;
+pub mod websocket;
@@ -1,52 +1,171 @@
 use axum::extract::State;
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::IntoResponse;
+use axum::Json;
 use axum_extra::{
     headers::{authorization::Basic, Authorization},
     TypedHeader,
 };
 use futures::stream::Stream;
-use secrecy::ExposeSecret;
+use prometheus::IntGauge;
 use std::convert::Infallible;
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+use uuid::Uuid;
 
+use crate::broadcaster::{ClientInfo, QueuedEvent};
+use crate::metrics::{BATCHES_SENT, CONFIRMATION_TIMEOUTS, SSE_CLIENTS_CONNECTED};
+use crate::routes::auth::credentials_match;
 use crate::webapp::WebApplicationState;
-use intersect_ingress_proxy_common::signals::wait_for_os_signal;
+use intersect_ingress_proxy_common::intersect_messaging::{
+    make_event_ids, make_eventsource_batch, strip_eventsource_metadata, AckRequest,
+    ACCEPT_BATCH_HEADER, ACCEPT_METADATA_HEADER, BATCH_EVENT, CLIENT_EVENT, CONFIRM_HEADER,
+};
+
+/// Keeps a connected clients gauge accurate, even if the client drops the stream instead of us closing it.
+pub(crate) struct ConnectedClientGuard(&'static IntGauge);
+
+impl ConnectedClientGuard {
+    pub(crate) fn new(gauge: &'static IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for ConnectedClientGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// how often we check for messages subscribers did not confirm in time
+pub(crate) const CONFIRMATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how often a draining subscriber checks whether it has sent (and had confirmed) everything, once shutdown is requested
+pub(crate) const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// requeue the messages a subscriber did not confirm in time
+pub(crate) fn expire_unconfirmed(info: &ClientInfo, timeout: Duration) {
+    let expired = info.expire_unconfirmed(timeout);
+    if expired > 0 {
+        CONFIRMATION_TIMEOUTS.inc_by(expired as u64);
+        tracing::warn!(
+            "client {} did not confirm {} messages within {:?}, they will be requeued",
+            info.id,
+            expired,
+            timeout
+        );
+    }
+}
+
+fn event_data(message: &QueuedEvent, with_metadata: bool) -> &str {
+    if with_metadata {
+        &message.data
+    } else {
+        strip_eventsource_metadata(&message.data)
+    }
+}
+
+/// Turn messages from the broadcaster into one SSE event: batches use the "batch" event type, single messages use the default.
+/// For clients which confirm messages, the event id is the message id (or the comma-separated message ids of a batch).
+/// Clients which don't accept metadata get the messages without it.
+fn make_event(messages: &[QueuedEvent], with_ids: bool, with_metadata: bool) -> Event {
+    let data = |message| event_data(message, with_metadata);
+    let event = match messages {
+        [message] => Event::default().data(data(message)),
+        _ => {
+            BATCHES_SENT.inc();
+            let data: Vec<&str> = messages.iter().map(data).collect();
+            Event::default()
+                .event(BATCH_EVENT)
+                .data(make_eventsource_batch(&data))
+        }
+    };
+    if !with_ids {
+        return event;
+    }
+    let ids: Vec<u64> = messages.iter().map(|message| message.id).collect();
+    event.id(make_event_ids(&ids))
+}
+
+/// whether a request header is set to "true"
+pub(crate) fn header_enabled(headers: &HeaderMap, name: &str) -> bool {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("true"))
+}
 
 fn sse_response(
     app_state: Arc<WebApplicationState>,
+    identity: &str,
+    accepts_batches: bool,
+    accepts_metadata: bool,
+    confirms: bool,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let mut rx = app_state.broadcaster.add_client();
+    let client = app_state.broadcaster.add_client(identity, confirms);
+    // clients which don't ask for batches get batches of one message, which are sent as plain events
+    let live = app_state.live();
+    let (max_messages, max_delay) = match (&live.batching, accepts_batches) {
+        (Some(batching), true) => (
+            batching.max_messages.max(1),
+            Duration::from_millis(batching.max_delay_ms),
+        ),
+        _ => (1, Duration::ZERO),
+    };
+    tracing::info!(
+        "SSE client {} connected as {} (batch size: {}, confirms messages: {})",
+        client.info.id,
+        identity,
+        max_messages,
+        confirms
+    );
 
     let stream = async_stream::stream! {
+        let _guard = ConnectedClientGuard::new(&SSE_CLIENTS_CONNECTED);
+        let info = client.info.clone();
+        if confirms {
+            // the client needs its id to confirm messages
+            yield Ok(Event::default().event(CLIENT_EVENT).data(info.id.to_string()));
+        }
+        let mut expiry = tokio::time::interval(CONFIRMATION_CHECK_INTERVAL);
+        let mut drain_check = tokio::time::interval(DRAIN_CHECK_INTERVAL);
+        let mut draining = false;
+        // a batch being collected is lost if its future is dropped, so it's kept across loop iterations
+        let mut next_batch = std::pin::pin!(client.recv_batch(max_messages, max_delay));
         loop {
             tokio::select! {
-                // if we catch an OS signal, disconnect the client
-                _ = wait_for_os_signal() => {
+                // on shutdown, keep going until the client has been sent (and confirmed) everything already queued for it
+                _ = app_state.shutdown.requested(), if !draining => draining = true,
+                _ = drain_check.tick(), if draining => {
+                    if info.is_drained() {
+                        break;
+                    }
+                },
+                _ = app_state.shutdown.deadline_passed() => break,
+                // an operator asked us to kick this client
+                _ = info.disconnect_requested() => {
+                    tracing::warn!("SSE client {} forcibly disconnected", info.id);
                     break;
                 },
-                // send the broadcast message to the client, and continue listening for more messages
-                // TODO figure out more robust mechanism to handle "lagged" errors from the receiver.
-                resp = rx.recv() => {
-                    match resp {
-                        Ok(event) => {
-                            yield Ok(event);
-                        },
-                        Err(e) => {
-                            match e {
-                                tokio::sync::broadcast::error::RecvError::Closed => {
-                                    tracing::error!(error = ?e, "Broadcasting pipeline to SSE somehow closed, should not see this message!")
-                                },
-                                tokio::sync::broadcast::error::RecvError::Lagged(lag_count) => {
-                                    tracing::error!(error = ?e, "SSE has missed {} messages from broadcaster", lag_count)
-                                },
-                            };
-                        },
-                    }
+                _ = expiry.tick(), if confirms => expire_unconfirmed(&info, app_state.live().confirmation_timeout),
+                // send the next queued message(s) to the client, and continue listening for more messages
+                messages = &mut next_batch => {
+                    next_batch.set(client.recv_batch(max_messages, max_delay));
+                    info.messages_sent.fetch_add(messages.len() as u64, Ordering::Relaxed);
+                    yield Ok(make_event(&messages, confirms, accepts_metadata));
                 },
             };
         };
+        let unconfirmed = info.unconfirmed_count();
+        if unconfirmed > 0 {
+            tracing::warn!(
+                "SSE client {} left without confirming {} messages, they will be requeued",
+                info.id,
+                unconfirmed
+            );
+        }
     };
 
     Sse::new(stream).keep_alive(KeepAlive::default())
@@ -58,11 +177,59 @@ fn sse_response(
 pub async fn sse_handler(
     State(app_state): State<Arc<WebApplicationState>>,
     TypedHeader(authorization): TypedHeader<Authorization<Basic>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let live = app_state.live();
+    if !credentials_match(&authorization, &live.username, &live.password) {
+        return (StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+    }
+    if app_state.shutdown.is_requested() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down").into_response();
+    }
+    sse_response(
+        app_state,
+        authorization.username(),
+        header_enabled(&headers, ACCEPT_BATCH_HEADER),
+        header_enabled(&headers, ACCEPT_METADATA_HEADER),
+        header_enabled(&headers, CONFIRM_HEADER),
+    )
+    .into_response()
+}
+
+/// Subscribers which connected with the "x-intersect-confirm" header confirm (or reject) the messages they were sent here.
+/// Confirmed messages are acknowledged on the broker, rejected ones are requeued.
+pub async fn ack_handler(
+    State(app_state): State<Arc<WebApplicationState>>,
+    TypedHeader(authorization): TypedHeader<Authorization<Basic>>,
+    Json(request): Json<AckRequest>,
 ) -> impl IntoResponse {
-    if authorization.username() != app_state.username
-        || authorization.password() != app_state.password.expose_secret()
-    {
+    let live = app_state.live();
+    if !credentials_match(&authorization, &live.username, &live.password) {
         return (StatusCode::UNAUTHORIZED, "unauthorized").into_response();
     }
-    sse_response(app_state).into_response()
+    // the client may have disconnected in the meantime, then its unconfirmed messages were already requeued
+    let Some(info) = Uuid::parse_str(&request.client)
+        .ok()
+        .and_then(|id| app_state.broadcaster.client(&id))
+        .filter(|info| info.confirms && info.identity == authorization.username())
+    else {
+        return (StatusCode::NOT_FOUND, "no such client").into_response();
+    };
+    let unknown = request.ack.iter().filter(|id| !info.confirm(**id)).count()
+        + request.nack.iter().filter(|id| !info.reject(**id)).count();
+    if unknown > 0 {
+        tracing::warn!(
+            "client {} answered for {} unknown (or expired) messages",
+            info.id,
+            unknown
+        );
+    }
+    if !request.nack.is_empty() {
+        tracing::warn!(
+            "client {} could not publish {} messages, they will be requeued",
+            info.id,
+            request.nack.len()
+        );
+    }
+    StatusCode::NO_CONTENT.into_response()
 }
@@ -0,0 +1,134 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum_extra::{
+    headers::{authorization::Basic, Authorization},
+    TypedHeader,
+};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+
+use crate::metrics::WS_CLIENTS_CONNECTED;
+use crate::routes::auth::credentials_match;
+use crate::routes::subscribe::{
+    expire_unconfirmed, header_enabled, ConnectedClientGuard, CONFIRMATION_CHECK_INTERVAL,
+    DRAIN_CHECK_INTERVAL,
+};
+use crate::webapp::WebApplicationState;
+use intersect_ingress_proxy_common::intersect_messaging::{
+    strip_eventsource_metadata, ACCEPT_METADATA_HEADER,
+};
+use intersect_ingress_proxy_common::protocols::websocket::{encode_message, Receipt};
+
+/// same interval axum uses for SSE keep-alive comments, so proxies don't close idle connections
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Send broadcast messages to a WebSocket subscriber until either side goes away.
+/// Messages are only acknowledged on the broker once the subscriber confirms them,
+/// anything it hasn't confirmed when it leaves (or within the confirmation timeout) is requeued.
+/// Subscribers which don't accept metadata get messages without it.
+async fn ws_session(
+    mut socket: WebSocket,
+    app_state: Arc<WebApplicationState>,
+    identity: String,
+    accepts_metadata: bool,
+) {
+    let _guard = ConnectedClientGuard::new(&WS_CLIENTS_CONNECTED);
+    let client = app_state.broadcaster.add_client(&identity, true);
+    let info = client.info.clone();
+    tracing::info!("WebSocket client {} connected as {}", info.id, identity);
+
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    let mut expiry = tokio::time::interval(CONFIRMATION_CHECK_INTERVAL);
+    let mut drain_check = tokio::time::interval(DRAIN_CHECK_INTERVAL);
+    let mut draining = false;
+    loop {
+        tokio::select! {
+            // on shutdown, keep going until the client has been sent (and confirmed) everything already queued for it
+            _ = app_state.shutdown.requested(), if !draining => draining = true,
+            _ = drain_check.tick(), if draining => {
+                if info.is_drained() {
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+            },
+            _ = app_state.shutdown.deadline_passed() => {
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            },
+            // an operator asked us to kick this client
+            _ = info.disconnect_requested() => {
+                tracing::warn!("WebSocket client {} forcibly disconnected", info.id);
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            },
+            _ = ping.tick() => {
+                if socket.send(Message::Ping(vec![])).await.is_err() {
+                    break;
+                }
+            },
+            _ = expiry.tick() => expire_unconfirmed(&info, app_state.live().confirmation_timeout),
+            event = client.recv() => {
+                let data = if accepts_metadata { &event.data } else { strip_eventsource_metadata(&event.data) };
+                if let Err(e) = socket.send(Message::Binary(encode_message(event.id, data))).await {
+                    tracing::warn!(error = ?e, "could not send message to WebSocket client {}", info.id);
+                    break;
+                }
+                info.messages_sent.fetch_add(1, Ordering::Relaxed);
+            },
+            frame = socket.recv() => {
+                match frame {
+                    Some(Ok(Message::Text(text))) => match serde_json::from_str::<Receipt>(&text) {
+                        Ok(Receipt::Ack(id)) => {
+                            if !info.confirm(id) {
+                                tracing::warn!("WebSocket client {} acknowledged unknown message {}", info.id, id);
+                            }
+                        },
+                        Ok(Receipt::Nack(id)) => {
+                            tracing::warn!("WebSocket client {} could not publish message {}", info.id, id);
+                            info.reject(id);
+                        },
+                        Err(e) => tracing::warn!(error = ?e, "WebSocket client {} sent an invalid receipt: {}", info.id, text),
+                    },
+                    Some(Ok(Message::Close(_))) | None => break,
+                    // pings are answered for us, and we don't expect anything else
+                    Some(Ok(_)) => {},
+                    Some(Err(e)) => {
+                        tracing::warn!(error = ?e, "WebSocket client {} connection failed", info.id);
+                        break;
+                    },
+                }
+            },
+        }
+    }
+    let unconfirmed = info.unconfirmed_count();
+    if unconfirmed > 0 {
+        tracing::warn!(
+            "WebSocket client {} left without confirming {} messages, they will be requeued",
+            info.id,
+            unconfirmed
+        );
+    }
+    tracing::info!("WebSocket client {} disconnected", info.id);
+}
+
+/// The WebSocket alternative to "/subscribe": binary frames, and the subscriber confirms every message.
+/// See shared-deps/src/protocols/websocket.rs for the frame format.
+pub async fn ws_handler(
+    State(app_state): State<Arc<WebApplicationState>>,
+    TypedHeader(authorization): TypedHeader<Authorization<Basic>>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let live = app_state.live();
+    if !credentials_match(&authorization, &live.username, &live.password) {
+        return (StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+    }
+    if app_state.shutdown.is_requested() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down").into_response();
+    }
+    let identity = authorization.username().to_owned();
+    let accepts_metadata = header_enabled(&headers, ACCEPT_METADATA_HEADER);
+    ws.on_upgrade(move |socket| ws_session(socket, app_state, identity, accepts_metadata))
+}
@@ -0,0 +1,213 @@
+/// A side channel showing operators what the consumer decided about each message (see "/admin/tap").
+/// Tap subscribers are not broadcaster clients: they never hold up acknowledgements, and a tap which falls behind just misses messages.
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+
+use crate::dead_letter::RejectionReason;
+
+/// how many messages a tap subscriber may fall behind before it misses some
+const TAP_CAPACITY: usize = 1024;
+
+/// What the consumer decided to do with a message
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TapDecision {
+    /// forwarded to subscribers
+    Passthrough,
+    /// from another System
+    ForeignSource,
+    /// UTF-8, but not an INTERSECT message
+    InvalidJson,
+    /// not UTF-8
+    InvalidUtf8,
+}
+
+impl From<RejectionReason> for TapDecision {
+    fn from(reason: RejectionReason) -> Self {
+        match reason {
+            RejectionReason::InvalidUtf8 => TapDecision::InvalidUtf8,
+            RejectionReason::InvalidJson => TapDecision::InvalidJson,
+            RejectionReason::ForeignSource => TapDecision::ForeignSource,
+        }
+    }
+}
+
+impl FromStr for TapDecision {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "passthrough" => Ok(TapDecision::Passthrough),
+            "foreign_source" => Ok(TapDecision::ForeignSource),
+            "invalid_json" => Ok(TapDecision::InvalidJson),
+            "invalid_utf8" => Ok(TapDecision::InvalidUtf8),
+            _ => Err(format!("unknown decision {:?}", value)),
+        }
+    }
+}
+
+/// What became of a message, once the consumer is done with it
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TapOutcome {
+    /// queued for at least one subscriber and acknowledged
+    Delivered,
+    /// queued for subscribers, acknowledged once they confirm it
+    AwaitingConfirmation,
+    /// nobody was subscribed, the no-subscriber policy decides what happens to it
+    Undelivered,
+    /// rejected and sent to the dead-letter exchange
+    DeadLettered,
+    /// rejected, but could not be dead-lettered, so it was requeued
+    Requeued,
+    /// rejected and acknowledged
+    Dropped,
+}
+
+/// One message the consumer received, as shown to tap subscribers
+#[derive(serde::Serialize, Debug)]
+pub struct TapEvent {
+    /// unix time in milliseconds
+    pub timestamp_ms: u64,
+    pub routing_key: String,
+    pub decision: TapDecision,
+    pub outcome: TapOutcome,
+    /// the message content, with anything which isn't UTF-8 replaced
+    pub content: String,
+}
+
+/// Where the consumer sends every message it receives while somebody is tapping
+pub struct Tap {
+    events: broadcast::Sender<Arc<TapEvent>>,
+}
+
+impl Default for Tap {
+    fn default() -> Self {
+        Self {
+            events: broadcast::channel(TAP_CAPACITY).0,
+        }
+    }
+}
+
+impl Tap {
+    /// whether anybody is tapping, so the consumer can skip copying messages otherwise
+    pub fn is_active(&self) -> bool {
+        self.events.receiver_count() > 0
+    }
+
+    /// Call this once the outcome is known, so subscribers see what actually happened to the message
+    pub fn publish(
+        &self,
+        routing_key: &str,
+        decision: TapDecision,
+        outcome: TapOutcome,
+        content: &[u8],
+    ) {
+        if !self.is_active() {
+            return;
+        }
+        let event = TapEvent {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or_default(),
+            routing_key: routing_key.to_owned(),
+            decision,
+            outcome,
+            content: String::from_utf8_lossy(content).into_owned(),
+        };
+        // nobody may be left by now, which is fine
+        let _ = self.events.send(Arc::new(event));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<TapEvent>> {
+        self.events.subscribe()
+    }
+}
+
+/// Which messages a tap subscriber wants to see
+pub struct TapSample {
+    /// only every n-th message which matches "decisions"
+    every: u64,
+    /// empty for all of them
+    decisions: Vec<TapDecision>,
+    matched: u64,
+}
+
+impl TapSample {
+    /// "every" below 1 is treated as 1
+    pub fn new(every: u64, decisions: Vec<TapDecision>) -> Self {
+        Self {
+            every: every.max(1),
+            decisions,
+            matched: 0,
+        }
+    }
+
+    /// whether to send this event to the tap subscriber
+    pub fn wants(&mut self, event: &TapEvent) -> bool {
+        if !self.decisions.is_empty() && !self.decisions.contains(&event.decision) {
+            return false;
+        }
+        self.matched += 1;
+        (self.matched - 1).is_multiple_of(self.every)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_every_nth_matching_message() {
+        let event = |decision| TapEvent {
+            timestamp_ms: 0,
+            routing_key: "organization.facility.system.userspace".to_owned(),
+            decision,
+            outcome: TapOutcome::Dropped,
+            content: String::new(),
+        };
+        let mut sample =
+            TapSample::new(2, vec![TapDecision::InvalidJson, TapDecision::InvalidUtf8]);
+        let wanted: Vec<bool> = [
+            TapDecision::InvalidJson,
+            TapDecision::Passthrough,
+            TapDecision::InvalidUtf8,
+            TapDecision::InvalidJson,
+            TapDecision::ForeignSource,
+        ]
+        .into_iter()
+        .map(|decision| sample.wants(&event(decision)))
+        .collect();
+        assert_eq!(wanted, [true, false, false, true, false]);
+    }
+
+    #[test]
+    fn events_carry_the_outcome() {
+        let tap = Tap::default();
+        tap.publish(
+            "dropped.before.anybody.taps",
+            TapDecision::Passthrough,
+            TapOutcome::Delivered,
+            b"{}",
+        );
+        let mut events = tap.subscribe();
+        tap.publish(
+            "organization.facility.system.userspace",
+            TapDecision::InvalidJson,
+            TapOutcome::DeadLettered,
+            b"not json",
+        );
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.routing_key, "organization.facility.system.userspace");
+        assert_eq!(
+            serde_json::to_value(&*event).unwrap()["outcome"],
+            "dead_lettered"
+        );
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,215 @@
+/// Messages which nobody was subscribed to receive are handled according to the configured NoSubscriberPolicy.
+/// In every case the message goes back to our queue eventually, so it is not lost.
+///
+/// Like the AckBatcher, this tracks delivery tags, so use a new UndeliveredHandler for each channel.
+use amqprs::{
+    channel::{BasicPublishArguments, Channel, QueueDeclareArguments},
+    BasicProperties, FieldTable, FieldValue,
+};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::acker::{AckBatcher, Acknowledge};
+use crate::configuration::{NoSubscriberPolicy, NoSubscriberSettings};
+use crate::dead_letter::ORIGINAL_ROUTING_KEY_HEADER;
+use crate::metrics::MESSAGES_REQUEUED;
+use intersect_ingress_proxy_common::protocols::amqp::compliant_queue_name;
+
+/// A message nobody received, with everything needed to republish it
+pub struct Undelivered {
+    pub delivery_tag: u64,
+    pub routing_key: String,
+    pub properties: Option<BasicProperties>,
+    pub content: Vec<u8>,
+}
+
+pub struct UndeliveredHandler {
+    policy: NoSubscriberPolicy,
+    delay: Duration,
+    /// queue we publish messages to for the "delay_queue" policy
+    delay_queue: String,
+    /// queue messages return to from the delay queue
+    queue: String,
+    /// when each message waiting for the "requeue" policy should be requeued, oldest first
+    pending_requeues: VecDeque<(Instant, u64)>,
+}
+
+impl UndeliveredHandler {
+    pub fn new(settings: &NoSubscriberSettings, queue: &str) -> Self {
+        Self {
+            policy: settings.policy,
+            delay: Duration::from_millis(settings.delay_ms.into()),
+            delay_queue: compliant_queue_name(&format!("{}.delay", queue)),
+            queue: queue.to_owned(),
+            pending_requeues: VecDeque::new(),
+        }
+    }
+
+    /// Declare the delay queue, if we use one. Messages expire from it back into our queue.
+    pub async fn declare(&self, channel: &Channel) -> Result<(), amqprs::error::Error> {
+        if self.policy != NoSubscriberPolicy::DelayQueue {
+            return Ok(());
+        }
+        let mut arguments = FieldTable::new();
+        arguments.insert(
+            "x-message-ttl".try_into().unwrap(),
+            FieldValue::l(self.delay.as_millis() as i64),
+        );
+        // the default exchange routes directly to the queue with the routing key's name
+        arguments.insert(
+            "x-dead-letter-exchange".try_into().unwrap(),
+            FieldValue::S("".try_into().unwrap()),
+        );
+        arguments.insert(
+            "x-dead-letter-routing-key".try_into().unwrap(),
+            FieldValue::S(self.queue.as_str().try_into().unwrap()),
+        );
+        channel
+            .queue_declare(
+                QueueDeclareArguments::durable_client_named(&self.delay_queue)
+                    .arguments(arguments)
+                    .finish(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Deal with a message nobody received. Returns true if we should stop consuming until a subscriber connects.
+    pub async fn handle(
+        &mut self,
+        channel: &Channel,
+        acker: &mut AckBatcher,
+        undelivered: Undelivered,
+    ) -> bool {
+        match self.policy {
+            NoSubscriberPolicy::Hold => {
+                acker.hold(undelivered.delivery_tag);
+                requeue(channel, acker, undelivered.delivery_tag, "hold").await;
+                true
+            }
+            NoSubscriberPolicy::Requeue => {
+                acker.hold(undelivered.delivery_tag);
+                self.pending_requeues
+                    .push_back((Instant::now() + self.delay, undelivered.delivery_tag));
+                false
+            }
+            NoSubscriberPolicy::DelayQueue => {
+                let delivery_tag = undelivered.delivery_tag;
+                match self.publish_to_delay_queue(channel, undelivered).await {
+                    Ok(_) => {
+                        MESSAGES_REQUEUED.with_label_values(&["delay_queue"]).inc();
+                        acker.ack(channel, delivery_tag).await;
+                    }
+                    Err(e) => {
+                        tracing::error!(error = ?e, "could not move delivery tag {} to the delay queue", delivery_tag);
+                        acker.hold(delivery_tag);
+                        requeue(channel, acker, delivery_tag, "delay_queue").await;
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    async fn publish_to_delay_queue(
+        &self,
+        channel: &Channel,
+        undelivered: Undelivered,
+    ) -> Result<(), amqprs::error::Error> {
+        // the message comes back with our queue's name as the routing key, so remember the real one
+        let mut properties = undelivered.properties.unwrap_or_default();
+        let mut headers = properties.headers().cloned().unwrap_or_default();
+        let key = ORIGINAL_ROUTING_KEY_HEADER.try_into().unwrap();
+        headers.remove(&key);
+        if let Ok(routing_key) = undelivered.routing_key.as_str().try_into() {
+            headers.insert(key, FieldValue::S(routing_key));
+        }
+        properties.with_headers(headers);
+        channel
+            .basic_publish(
+                properties,
+                undelivered.content,
+                BasicPublishArguments::new("", &self.delay_queue),
+            )
+            .await
+    }
+
+    /// Resolves once the oldest message waiting to be requeued is due, never resolves if there are none
+    pub async fn next_requeue(&self) {
+        match self.pending_requeues.front() {
+            Some((due, _)) => tokio::time::sleep_until((*due).into()).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// requeue every message which has waited long enough
+    pub async fn requeue_due(&mut self, channel: &Channel, acker: &mut AckBatcher) {
+        for delivery_tag in self.take_due(Instant::now()) {
+            requeue(channel, acker, delivery_tag, "requeue").await;
+        }
+    }
+
+    /// requeue every waiting message now, i.e. because we're shutting down
+    pub async fn requeue_all(&mut self, channel: &Channel, acker: &mut AckBatcher) {
+        for (_, delivery_tag) in std::mem::take(&mut self.pending_requeues) {
+            requeue(channel, acker, delivery_tag, "requeue").await;
+        }
+    }
+
+    fn take_due(&mut self, now: Instant) -> Vec<u64> {
+        let due = self
+            .pending_requeues
+            .iter()
+            .take_while(|(due, _)| *due <= now)
+            .count();
+        self.pending_requeues
+            .drain(..due)
+            .map(|(_, delivery_tag)| delivery_tag)
+            .collect()
+    }
+}
+
+/// put a held message back on our queue, "policy" labels the requeued messages metric
+pub async fn requeue(
+    channel: &impl Acknowledge,
+    acker: &mut AckBatcher,
+    delivery_tag: u64,
+    policy: &str,
+) {
+    match channel.send_nack(delivery_tag, true).await {
+        Ok(_) => {
+            MESSAGES_REQUEUED.with_label_values(&[policy]).inc();
+            acker.release(delivery_tag);
+        }
+        // the channel is most likely gone, so the broker requeues it anyways
+        Err(e) => tracing::error!(error = ?e, "could not requeue delivery tag {}", delivery_tag),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_due_messages_are_requeued() {
+        let mut handler = UndeliveredHandler::new(
+            &NoSubscriberSettings {
+                policy: NoSubscriberPolicy::Requeue,
+                delay_ms: 1000,
+            },
+            "broker-2-http.organization.facility.system",
+        );
+        let start = Instant::now();
+        handler.pending_requeues.extend([
+            (start, 1),
+            (start + Duration::from_secs(1), 2),
+            (start + Duration::from_secs(2), 3),
+        ]);
+
+        assert_eq!(handler.take_due(start + Duration::from_secs(1)), vec![1, 2]);
+        assert!(handler.take_due(start + Duration::from_secs(1)).is_empty());
+        assert_eq!(handler.take_due(start + Duration::from_secs(5)), vec![3]);
+    }
+}
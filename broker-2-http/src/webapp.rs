@@ -1,9 +1,18 @@
-use axum::{routing::get, serve::Serve, Router};
+use axum::{
+    http::{Extensions, HeaderMap, StatusCode, Version},
+    routing::{get, post},
+    serve::Serve,
+    Router,
+};
 use secrecy::Secret;
-use std::sync::Arc;
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 use tokio::net::TcpListener;
 use tower::ServiceBuilder;
 use tower_http::{
+    compression::{CompressionLayer, Predicate},
     request_id::MakeRequestUuid,
     trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
     ServiceBuilderExt,
@@ -11,33 +20,97 @@ use tower_http::{
 use tracing::Level;
 
 use crate::{
+    amqp_consumer::ConsumerControl,
     broadcaster::Broadcaster,
-    configuration::Settings,
-    routes::{health_check::health_check, not_found::handler_404, subscribe::sse_handler},
+    configuration::{AdminSettings, BatchSettings, Settings},
+    routes::{
+        admin::admin_router,
+        health_check::health_check,
+        not_found::handler_404,
+        subscribe::{ack_handler, sse_handler},
+        websocket::ws_handler,
+    },
 };
 
-use intersect_ingress_proxy_common::signals::wait_for_os_signal;
+use intersect_ingress_proxy_common::{
+    health::{livez_handler, readyz_handler, HealthState},
+    metrics::metrics_handler,
+    signals::Shutdown,
+    telemetry::LogLevelHandle,
+};
 
-/// This is state that can be accessed by any endpoint on the server.
-pub struct WebApplicationState {
-    /// this broadcaster gets messages published to it from one source and can publish many messages from it
-    pub broadcaster: Arc<Broadcaster>,
+/// Settings endpoints use which can change when the configuration is reloaded.
+/// They are replaced all at once, so a request never sees half of a reload.
+pub struct LiveSettings {
     /// basic auth username
     pub username: String,
     /// basic auth password
     pub password: Secret<String>,
+    /// admin API credentials, the admin API is not served if this was None at startup
+    pub admin: Option<AdminSettings>,
+    /// batching for subscribers which support it, disabled if None. Subscribers keep what they connected with.
+    pub batching: Option<BatchSettings>,
+    /// how long subscribers have to confirm a message, if they confirm messages
+    pub confirmation_timeout: Duration,
+}
+
+impl LiveSettings {
+    fn new(configuration: &Settings) -> Self {
+        Self {
+            username: configuration.username.clone(),
+            password: configuration.password.clone(),
+            admin: configuration.admin.clone(),
+            batching: configuration.batching.clone(),
+            confirmation_timeout: Duration::from_millis(configuration.confirmation_timeout_ms),
+        }
+    }
+}
+
+/// This is state that can be accessed by any endpoint on the server.
+pub struct WebApplicationState {
+    /// this broadcaster gets messages published to it from one source and can publish many messages from it
+    pub broadcaster: Arc<Broadcaster>,
+    /// credentials and subscriber settings, see "live()"
+    live: RwLock<Arc<LiveSettings>>,
+    /// broker connection state
+    pub health: Arc<HealthState>,
+    /// lets the admin API pause and resume broker consumption
+    pub consumer_control: Arc<ConsumerControl>,
+    /// lets the admin API change the log level
+    pub log_level: LogLevelHandle,
+    /// subscribers are disconnected once they're drained after shutdown is requested
+    pub shutdown: Shutdown,
+}
+
+impl WebApplicationState {
+    /// The current credentials and subscriber settings, don't hold on to them beyond the request
+    pub fn live(&self) -> Arc<LiveSettings> {
+        self.live.read().unwrap().clone()
+    }
+
+    /// Replace the credentials and subscriber settings with those of a reloaded configuration
+    pub fn reload(&self, configuration: &Settings) {
+        *self.live.write().unwrap() = Arc::new(LiveSettings::new(configuration));
+    }
 }
 
 type WebAppServer = Serve<Router, Router>;
 pub struct WebApplication {
     pub port: u16,
     pub server: WebAppServer,
+    state: Arc<WebApplicationState>,
+    shutdown: Shutdown,
+    broadcaster: Arc<Broadcaster>,
 }
 
 impl WebApplication {
     pub async fn build(
         configuration: &Settings,
         broadcaster: Arc<Broadcaster>,
+        health: Arc<HealthState>,
+        consumer_control: Arc<ConsumerControl>,
+        log_level: LogLevelHandle,
+        shutdown: Shutdown,
     ) -> Result<Self, anyhow::Error> {
         let address = format!(
             "{}:{}",
@@ -50,30 +123,74 @@ impl WebApplication {
         );
         let listener = TcpListener::bind(address).await?;
         let port = listener.local_addr().unwrap().port();
-        let server = run(listener, configuration, broadcaster).await?;
+        let (server, state) = run(
+            listener,
+            configuration,
+            broadcaster.clone(),
+            health,
+            consumer_control,
+            log_level,
+            shutdown.clone(),
+        )
+        .await?;
 
         tracing::info!("Web server is running on port {}", port);
 
-        Ok(Self { port, server })
+        Ok(Self {
+            port,
+            server,
+            state,
+            shutdown,
+            broadcaster,
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
+    /// for applying a reloaded configuration, see "WebApplicationState::reload"
+    pub fn state(&self) -> Arc<WebApplicationState> {
+        self.state.clone()
+    }
+
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
         // the return type of "with_graceful_shutdown" is unstable, so set it up here
         self.server
-            .with_graceful_shutdown(wait_for_os_signal())
+            .with_graceful_shutdown(subscribers_gone(self.shutdown, self.broadcaster))
             .await
     }
 }
 
+/// Resolves once shutdown was requested and every subscriber has drained and disconnected (or the shutdown deadline passed).
+/// Until then we keep accepting connections, so SSE subscribers can still confirm messages with "POST /ack".
+async fn subscribers_gone(shutdown: Shutdown, broadcaster: Arc<Broadcaster>) {
+    shutdown.requested().await;
+    let mut client_count = broadcaster.watch_client_count();
+    tokio::select! {
+        _ = client_count.wait_for(|count| *count == 0) => {},
+        _ = shutdown.deadline_passed() => {},
+    }
+}
+
+/// Compression for the SSE stream, using whichever algorithm the subscriber prefers.
+/// tower-http's default predicate never compresses event streams, since compressors buffer their output.
+/// Its encoder flushes whenever our stream has nothing else ready to send, though, so every event still goes out immediately.
+fn sse_compression() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new().compress_when(
+        |status: StatusCode, _: Version, _: &HeaderMap, _: &Extensions| status.is_success(),
+    )
+}
+
 async fn run(
     listener: TcpListener,
     configuration: &Settings,
     broadcaster: Arc<Broadcaster>,
-) -> Result<WebAppServer, anyhow::Error> {
+    health: Arc<HealthState>,
+    consumer_control: Arc<ConsumerControl>,
+    log_level: LogLevelHandle,
+    shutdown: Shutdown,
+) -> Result<(WebAppServer, Arc<WebApplicationState>), anyhow::Error> {
     let middleware = ServiceBuilder::new()
         .set_x_request_id(MakeRequestUuid)
         .layer(
@@ -89,18 +206,86 @@ async fn run(
 
     let app_state = Arc::new(WebApplicationState {
         broadcaster,
-        username: configuration.username.clone(),
-        password: configuration.password.clone(),
+        live: RwLock::new(Arc::new(LiveSettings::new(configuration))),
+        health: health.clone(),
+        consumer_control,
+        log_level,
+        shutdown,
     });
 
-    let app = Router::new()
-        .route("/subscribe", get(sse_handler))
-        //.route("/publish", post(publish))
+    let subscribe = if configuration.compression {
+        get(sse_handler).layer(sse_compression())
+    } else {
+        get(sse_handler)
+    };
+    let mut app = Router::new()
+        .route("/subscribe", subscribe)
+        .route("/ws", get(ws_handler))
+        .route("/ack", post(ack_handler));
+    //.route("/publish", post(publish))
+    if configuration.admin.is_some() {
+        app = app.nest("/admin", admin_router(app_state.clone()));
+    } else {
+        tracing::info!("No admin credentials configured, admin API is disabled");
+    }
+
+    let app = app
         .layer(middleware) // routes added before this layer will be logged, after this layer will not be logged
-        .with_state(app_state)
+        .with_state(app_state.clone())
         .route("/healthcheck", get(health_check))
+        .route("/livez", get(livez_handler).with_state(health.clone()))
+        .route("/readyz", get(readyz_handler).with_state(health))
+        .route("/metrics", get(metrics_handler))
         .fallback(handler_404);
 
     let server = axum::serve(listener, app);
-    Ok(server)
+    Ok((server, app_state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        http::{header, Request},
+        response::sse::{Event, Sse},
+    };
+    use futures::StreamExt;
+    use std::{convert::Infallible, time::Duration};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn compressed_events_are_flushed_immediately() {
+        // one event, then the stream stays open without sending anything else
+        let app: Router = Router::new().route(
+            "/subscribe",
+            get(|| async {
+                let events = futures::stream::once(async {
+                    Ok::<_, Infallible>(Event::default().data("hello"))
+                })
+                .chain(futures::stream::pending());
+                Sse::new(events)
+            })
+            .layer(sse_compression()),
+        );
+
+        let response = app
+            .oneshot(
+                Request::get("/subscribe")
+                    .header(header::ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let mut body = response.into_body().into_data_stream();
+        let chunk = tokio::time::timeout(Duration::from_secs(1), body.next())
+            .await
+            .expect("the event was not flushed")
+            .unwrap()
+            .unwrap();
+        assert!(!chunk.is_empty());
+    }
 }
@@ -0,0 +1,123 @@
+/// Replay a recording broker-2-http made of what it forwarded into our broker, the way http-2-broker publishes messages.
+/// Uses the same configuration (and command line options) as http-2-broker.
+///
+/// Usage:
+///   http-2-broker-replay [OPTIONS] <RECORDING>
+///   i.e. http-2-broker-replay -c http-2-broker/conf.yaml --rate 10 --rewrite organization.facility.system=organization.facility.test recording.jsonl
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::Parser;
+
+use http_2_broker::configuration::RewriteRule;
+use http_2_broker::configuration::Settings;
+use http_2_broker::replay::{replay, ReplayOptions};
+use http_2_broker::rewrite::TopicRewriter;
+use http_2_broker::subscriber::{declare_exchange, BrokerData};
+use intersect_ingress_proxy_common::cli::Cli;
+use intersect_ingress_proxy_common::configuration::configuration_or_exit;
+use intersect_ingress_proxy_common::health::{
+    HealthState, BROKER_COMPONENT, DEFAULT_MAX_HEARTBEAT_AGE,
+};
+use intersect_ingress_proxy_common::protocols::amqp::SharedConnection;
+use intersect_ingress_proxy_common::signals::Shutdown;
+use intersect_ingress_proxy_common::telemetry::{get_pretty_subscriber, init_subscriber};
+
+/// Replay a recording broker-2-http made (see its "recording" setting) into our broker
+#[derive(Parser)]
+#[command(name = "http-2-broker-replay", version)]
+struct Args {
+    /// JSON lines recorded by broker-2-http
+    recording: PathBuf,
+    /// Publish at most this many messages per second (default: as fast as possible)
+    #[arg(long)]
+    rate: Option<f64>,
+    /// Publish messages whose routing key starts with FROM with TO instead, i.e. "organization.facility.system=organization.facility.test".
+    /// Can be repeated, the first match wins, and these come before the configured "topic_rewrites". Only the routing key changes, not the message.
+    #[arg(long = "rewrite", value_name = "FROM=TO")]
+    rewrites: Vec<RewriteRule>,
+    /// Print the routing key and message of everything which would be published, without connecting to the broker
+    #[arg(long)]
+    dry_run: bool,
+    #[command(flatten)]
+    cli: Cli,
+}
+
+impl Args {
+    /// "--print-default-config" prints http-2-broker's example configuration, as this reads the same settings
+    fn with_default_config(mut self) -> Self {
+        self.cli.default_config = include_str!("../../conf.yaml");
+        self
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse().with_default_config();
+    let configuration = configuration_or_exit(&args.cli, Cli::read_configuration::<Settings>);
+    let (subscriber, _) = get_pretty_subscriber(configuration.log_level.to_string(), None);
+    init_subscriber(subscriber);
+
+    let recording = BufReader::new(File::open(&args.recording)?);
+    let mut rules = args.rewrites;
+    rules.extend(configuration.topic_rewrites.iter().cloned());
+    let options = ReplayOptions {
+        rate: args.rate,
+        // the configuration was validated, so every pattern compiles
+        rewriter: TopicRewriter::new(&rules)?,
+        dry_run: args.dry_run,
+    };
+
+    let shutdown = Shutdown::new();
+    shutdown.request_on_os_signal();
+    let connection = SharedConnection::new(configuration.broker.clone());
+    let broker_data = Arc::new(BrokerData {
+        connection: connection.clone(),
+        health: HealthState::new(&[BROKER_COMPONENT], DEFAULT_MAX_HEARTBEAT_AGE),
+        shutdown,
+    });
+    if !options.dry_run {
+        declare_exchange(&connection.get(1).await).await?;
+    }
+
+    let summary = replay(&configuration, recording, &options, broker_data).await;
+    if !options.dry_run {
+        connection.close().await;
+    }
+    let summary = summary?;
+    eprintln!(
+        "{} {}, {} failed, {} lines skipped",
+        summary.published,
+        if options.dry_run {
+            "would be published"
+        } else {
+            "published"
+        },
+        summary.failed,
+        summary.invalid
+    );
+    if summary.failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prints_the_http_2_broker_default_config() {
+        let args = Args::try_parse_from([
+            "http-2-broker-replay",
+            "--print-default-config",
+            "recording.jsonl",
+        ])
+        .unwrap()
+        .with_default_config();
+        assert!(args.cli.print_default_config);
+        assert!(args.cli.default_config.contains("other_proxy:"));
+    }
+}
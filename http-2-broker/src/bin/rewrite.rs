@@ -0,0 +1,56 @@
+//! Show what the configured "topic_rewrites" do to routing keys (and messages), without connecting to anything.
+//! Uses the same configuration (and command line options) as http-2-broker.
+//!
+//! Usage:
+//!   http-2-broker-rewrite [OPTIONS] [ROUTING_KEY]...
+//!   i.e. http-2-broker-rewrite -c http-2-broker/conf.yaml partner.facility.system.userspace
+//!   or, with messages: echo 'partner.facility.system.userspace {"headers": {...}, ...}' | http-2-broker-rewrite -c http-2-broker/conf.yaml
+use std::io::BufRead;
+
+use clap::Parser;
+
+use http_2_broker::configuration::Settings;
+use http_2_broker::rewrite::TopicRewriter;
+use intersect_ingress_proxy_common::cli::Cli;
+use intersect_ingress_proxy_common::configuration::configuration_or_exit;
+
+/// Print "ROUTING_KEY -> REWRITTEN_ROUTING_KEY" for every routing key, as http-2-broker would publish it
+#[derive(Parser)]
+#[command(name = "http-2-broker-rewrite", version)]
+struct Args {
+    /// routing keys to rewrite. Without any, lines of "ROUTING_KEY [MESSAGE]" are read from stdin,
+    /// and the rewritten message is printed after the routing keys.
+    routing_keys: Vec<String>,
+    #[command(flatten)]
+    cli: Cli,
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    args.cli.default_config = include_str!("../../conf.yaml");
+    let configuration = configuration_or_exit(&args.cli, Cli::read_configuration::<Settings>);
+    // the configuration was validated, so every pattern compiles
+    let rewriter = TopicRewriter::new(&configuration.topic_rewrites)?;
+
+    if !args.routing_keys.is_empty() {
+        for routing_key in &args.routing_keys {
+            println!("{} -> {}", routing_key, rewriter.rewrite_topic(routing_key));
+        }
+        return Ok(());
+    }
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.split_once(' ') {
+            Some((routing_key, message)) => {
+                let (rewritten, message) = rewriter.rewrite(routing_key, message.to_owned());
+                println!("{} -> {} {}", routing_key, rewritten, message);
+            }
+            None => println!("{} -> {}", line, rewriter.rewrite_topic(line)),
+        }
+    }
+    Ok(())
+}
@@ -1,30 +1,221 @@
 /// FOR DEVOPS USERS:
 /// 1) The root struct is "Settings", follow logic from there
 /// 2) integers can be provided as a string in config files or environment variables
-/// 3) if using environment variables, see comment in "get_configuration()" as an example of how nesting works
-/// 4) if using ONLY a file variable, this is determined from the APP_CONFIG_FILE environment variable (environment variables have higher precedence)
+/// 3) if using environment variables, see comment in "Cli::read_configuration()" as an example of how nesting works
+/// 4) the file is given with "--config" or the APP_CONFIG_FILE environment variable; environment variables have higher precedence, the command line ("--set ...") highest
 /// 5) Additional logic can be found in shared-deps/src/configuration.rs
-use intersect_ingress_proxy_common::configuration::{BrokerSettings, LogLevel};
+use intersect_ingress_proxy_common::configuration::{
+    serialize_redacted, setting_path, BrokerSettings, ConfigProblems, LogLevel, Validate,
+};
+use intersect_ingress_proxy_common::reload::{compare_secret, Reload};
 use secrecy::Secret;
+use serde_aux::field_attributes::deserialize_number_from_string;
 
-#[derive(serde::Deserialize, Clone)]
+/// How we receive messages from the other proxy
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// Server-Sent Events from "/subscribe", messages are confirmed with "POST /ack" if "confirm" is set (otherwise acknowledged as soon as broker-2-http sends them)
+    Sse,
+    /// WebSocket from "/ws", broker-2-http only acknowledges messages once we confirm we published them
+    Websocket,
+}
+
+fn default_transport() -> Transport {
+    Transport::Sse
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct ExternalProxy {
-    /// URL for the other ingress proxy we are communicating with
+    /// URL for the other ingress proxy we are communicating with ("http(s)://.../subscribe" for SSE, "ws(s)://.../ws" for WebSocket)
     pub url: String,
+    #[serde(default = "default_transport")]
+    pub transport: Transport,
     /// Basic authentication credentials for the other proxy
     pub username: String,
     /// Basic authentication credentials for the other proxy
+    #[serde(serialize_with = "serialize_redacted")]
     pub password: Secret<String>,
+    /// ask the other proxy to batch several messages into one event, if it is configured to (SSE only)
+    #[serde(default = "default_true")]
+    pub accept_batches: bool,
+    /// Only confirm messages to the other proxy once our broker has confirmed them, so the other proxy keeps them until then.
+    /// With SSE, the other proxy is asked to wait for our confirmations (WebSocket always waits for them).
+    #[serde(default = "default_true")]
+    pub confirm: bool,
+    /// where to send confirmations with SSE, by default "ack" next to the subscribe URL
+    pub ack_url: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
 }
 
-#[derive(serde::Deserialize, Clone)]
+/// Publish messages with a different routing key than the other proxy sent, i.e. because the other facility names its Systems differently.
+/// Exactly one of "prefix" or "pattern" is set.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct RewriteRule {
+    /// routing keys starting with these whole words, i.e. "a.b" matches "a.b" and "a.b.c", but not "a.bc"
+    pub prefix: Option<String>,
+    /// a regular expression the whole routing key has to match
+    pub pattern: Option<String>,
+    /// replaces the prefix, or the whole routing key for a pattern ("$1" or "${name}" are replaced by what the pattern captured)
+    pub replacement: String,
+    /// also rewrite "headers.source" and "headers.destination" in the message
+    #[serde(default)]
+    pub headers: bool,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct Settings {
     /// configuration for the broker, which our applications are listening to
     pub broker: BrokerSettings, // TODO make this a Vec<BrokerSettings>
     /// URL for the other ingress proxy we are communicating with
     pub other_proxy: ExternalProxy, // TODO make this a Vec<ExternalProxy>
+    #[serde(
+        default = "default_app_port",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    /// port for our health and metrics server (this application does not otherwise serve HTTP)
+    pub app_port: u16,
     /// log level for the entire application
     pub log_level: LogLevel,
+    /// how long we wait for the broker to confirm a message we published, if "other_proxy.confirm" is set
+    #[serde(
+        default = "default_publish_confirm_timeout_ms",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub publish_confirm_timeout_ms: u64,
+    /// on shutdown, how long we keep publishing and confirming the messages we already received
+    #[serde(
+        default = "default_shutdown_timeout_ms",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub shutdown_timeout_ms: u64,
     /// set to true for developer-unfriendly settings (currently just log formats)
     pub production: bool,
+    /// full URL of an OpenTelemetry collector's OTLP/HTTP traces endpoint (i.e. "http://localhost:4318/v1/traces").
+    /// If not set, spans are not exported.
+    pub otlp_endpoint: Option<String>,
+    /// applied to every message before we publish it, the first rule which matches wins (see "http-2-broker-rewrite" to try them out)
+    #[serde(default)]
+    pub topic_rewrites: Vec<RewriteRule>,
+}
+
+fn default_publish_confirm_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_shutdown_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_app_port() -> u16 {
+    8081
+}
+
+impl Validate for ExternalProxy {
+    fn validate(&self, path: &str, problems: &mut ConfigProblems) {
+        let schemes: &[&str] = match self.transport {
+            Transport::Sse => &["http", "https"],
+            Transport::Websocket => &["ws", "wss"],
+        };
+        problems.check_url(&setting_path(path, "url"), &self.url, schemes);
+        problems.check_not_empty(&setting_path(path, "username"), &self.username);
+        if let Some(ack_url) = &self.ack_url {
+            problems.check_url(&setting_path(path, "ack_url"), ack_url, &["http", "https"]);
+        }
+    }
+}
+
+impl Validate for RewriteRule {
+    fn validate(&self, path: &str, problems: &mut ConfigProblems) {
+        match (&self.prefix, &self.pattern) {
+            (Some(prefix), None) => problems.check_not_empty(&setting_path(path, "prefix"), prefix),
+            (None, Some(pattern)) => {
+                if let Err(e) = regex::Regex::new(pattern) {
+                    problems.add(&setting_path(path, "pattern"), e.to_string());
+                }
+            }
+            _ => problems.add(path, "needs exactly one of \"prefix\" or \"pattern\""),
+        }
+    }
+}
+
+impl Validate for Settings {
+    fn validate(&self, path: &str, problems: &mut ConfigProblems) {
+        self.broker
+            .validate(&setting_path(path, "broker"), problems);
+        self.other_proxy
+            .validate(&setting_path(path, "other_proxy"), problems);
+        problems.check_port(&setting_path(path, "app_port"), self.app_port);
+        problems.check_positive(
+            &setting_path(path, "publish_confirm_timeout_ms"),
+            self.publish_confirm_timeout_ms,
+        );
+        if let Some(otlp_endpoint) = &self.otlp_endpoint {
+            problems.check_url(
+                &setting_path(path, "otlp_endpoint"),
+                otlp_endpoint,
+                &["http", "https"],
+            );
+        }
+        for (index, rule) in self.topic_rewrites.iter().enumerate() {
+            rule.validate(
+                &format!("{}[{}]", setting_path(path, "topic_rewrites"), index),
+                problems,
+            );
+        }
+    }
+}
+
+impl Reload for Settings {
+    /// changing anything in "other_proxy" or "topic_rewrites" reconnects to the other proxy
+    const RELOADABLE: &'static [&'static str] = &["log_level", "other_proxy", "topic_rewrites"];
+
+    fn changed_secrets(&self, new: &Self, path: &str, changed: &mut Vec<String>) {
+        compare_secret(
+            &setting_path(path, "broker.password"),
+            &self.broker.password,
+            &new.broker.password,
+            changed,
+        );
+        compare_secret(
+            &setting_path(path, "other_proxy.password"),
+            &self.other_proxy.password,
+            &new.other_proxy.password,
+            changed,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_scheme_must_match_transport() {
+        let settings: Settings = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                broker: { username: user, password: pass, host: localhost, port: 5672 }
+                other_proxy:
+                  url: "http://localhost:8080/ws"
+                  transport: websocket
+                  username: user
+                  password: pass
+                log_level: info
+                production: false
+                "#,
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let mut problems = ConfigProblems::new();
+        settings.validate("", &mut problems);
+        let paths: Vec<&str> = problems.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(paths, ["other_proxy.url"]);
+    }
 }
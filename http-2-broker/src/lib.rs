@@ -1 +1,6 @@
 pub mod configuration;
+pub mod metrics;
+pub mod replay;
+pub mod rewrite;
+pub mod subscriber;
+pub mod webapp;
@@ -0,0 +1,92 @@
+/// Prometheus metrics specific to http-2-broker. These are exposed on the "/metrics" endpoint.
+use std::sync::LazyLock;
+
+use prometheus::{register_histogram, register_int_counter, Histogram, IntCounter};
+
+/// every message we received from the other proxy, regardless of what we did with it (messages in a batch are counted individually)
+pub static EVENTS_RECEIVED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "http2broker_events_received_total",
+        "Number of messages received from the other proxy"
+    )
+    .unwrap()
+});
+
+/// SSE messages which could not be turned into a broker message
+pub static INVALID_EVENTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "http2broker_invalid_events_total",
+        "Number of messages from the other proxy which could not be published"
+    )
+    .unwrap()
+});
+
+/// messages published with a different routing key than the other proxy sent, see "topic_rewrites"
+pub static TOPICS_REWRITTEN: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "http2broker_topics_rewritten_total",
+        "Number of messages published with a rewritten routing key"
+    )
+    .unwrap()
+});
+
+pub static MESSAGES_PUBLISHED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "http2broker_messages_published_total",
+        "Number of messages published to the broker"
+    )
+    .unwrap()
+});
+
+pub static PUBLISH_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "http2broker_publish_failures_total",
+        "Number of messages which failed to publish to the broker"
+    )
+    .unwrap()
+});
+
+/// time spent publishing a single message, including opening and closing its channel
+pub static PUBLISH_LATENCY: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "http2broker_publish_latency_seconds",
+        "Time taken to publish a message to the broker"
+    )
+    .unwrap()
+});
+
+/// size of the event source data we received from the other proxy
+pub static BYTES_RECEIVED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "http2broker_bytes_received_total",
+        "Number of event source data bytes received from the other proxy"
+    )
+    .unwrap()
+});
+
+/// SSE events from the other proxy which contained a batch of messages
+pub static BATCHES_RECEIVED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "http2broker_batches_received_total",
+        "Number of message batches received from the other proxy"
+    )
+    .unwrap()
+});
+
+/// requests confirming messages to the other proxy (each may confirm several messages)
+pub static CONFIRMATIONS_SENT: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "http2broker_confirmations_sent_total",
+        "Number of requests confirming published messages to the other proxy"
+    )
+    .unwrap()
+});
+
+/// failed confirmation requests, the other proxy will requeue (and send again) the messages they covered
+pub static CONFIRMATION_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "http2broker_confirmation_failures_total",
+        "Number of requests confirming messages to the other proxy which failed"
+    )
+    .unwrap()
+});
@@ -0,0 +1,139 @@
+/// Replaying a recording broker-2-http made (see its "recording" setting) into our broker,
+/// through the same publish path as messages we receive from the other proxy.
+use std::io::BufRead;
+use std::sync::Arc;
+use std::time::Duration;
+
+use intersect_ingress_proxy_common::recording::RecordedEvent;
+
+use crate::configuration::Settings;
+use crate::rewrite::TopicRewriter;
+use crate::subscriber::{publish_message, rewrite_message, BrokerData};
+
+/// How to replay a recording
+pub struct ReplayOptions {
+    /// at most this many messages per second, as fast as possible if None
+    pub rate: Option<f64>,
+    /// usually "--rewrite" rules followed by "topic_rewrites"
+    pub rewriter: TopicRewriter,
+    /// only print what would be published
+    pub dry_run: bool,
+}
+
+/// What happened to the messages of a recording
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub published: usize,
+    pub failed: usize,
+    /// lines which aren't recorded events
+    pub invalid: usize,
+}
+
+/// Publish every message in "recording", in order. Stops early (without an error) once shutdown is requested.
+/// Messages the broker refuses are counted as failed, we carry on with the rest.
+pub async fn replay(
+    configuration: &Settings,
+    recording: impl BufRead,
+    options: &ReplayOptions,
+    broker_data: Arc<BrokerData>,
+) -> std::io::Result<ReplaySummary> {
+    let mut summary = ReplaySummary::default();
+    let mut pace = options
+        .rate
+        .filter(|rate| *rate > 0.0)
+        .map(|rate| tokio::time::interval(Duration::from_secs_f64(1.0 / rate)));
+    for (index, line) in recording.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: RecordedEvent = match serde_json::from_str(&line) {
+            Ok(event) => event,
+            Err(e) => {
+                tracing::warn!(error = ?e, "line {} is not a recorded message, skipping it", index + 1);
+                summary.invalid += 1;
+                continue;
+            }
+        };
+        let (routing_key, envelope) = rewrite_message(
+            &options.rewriter,
+            event.routing_key,
+            event.envelope.get().to_owned(),
+        );
+        if options.dry_run {
+            println!("{} {}", routing_key, envelope);
+            summary.published += 1;
+            continue;
+        }
+        if let Some(pace) = &mut pace {
+            tokio::select! {
+                _ = pace.tick() => {},
+                _ = broker_data.shutdown.requested() => break,
+            }
+        } else if broker_data.shutdown.is_requested() {
+            break;
+        }
+        if publish_message(configuration, routing_key, envelope, broker_data.clone()).await {
+            summary.published += 1;
+        } else {
+            summary.failed += 1;
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use intersect_ingress_proxy_common::health::{
+        HealthState, BROKER_COMPONENT, DEFAULT_MAX_HEARTBEAT_AGE,
+    };
+    use intersect_ingress_proxy_common::protocols::amqp::SharedConnection;
+    use intersect_ingress_proxy_common::signals::Shutdown;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn replays_recorded_multi_line_messages() {
+        let configuration: Settings = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                broker: { username: user, password: pass, host: localhost, port: 5672 }
+                other_proxy: { url: "http://localhost:8080/subscribe", username: user, password: pass }
+                log_level: info
+                production: false
+                "#,
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let mut recording = String::new();
+        for payload in ["first", "second"] {
+            let envelope = format!(
+                "{{\n  \"headers\": {{\n    \"source\": \"organization.facility.system\"\n  }},\n  \"payload\": \"{}\"\n}}",
+                payload
+            );
+            let event =
+                RecordedEvent::new("organization.facility.system.userspace", &envelope).unwrap();
+            recording.push_str(&serde_json::to_string(&event).unwrap());
+            recording.push('\n');
+        }
+        let options = ReplayOptions {
+            rate: None,
+            rewriter: TopicRewriter::default(),
+            dry_run: true,
+        };
+        let broker_data = Arc::new(BrokerData {
+            connection: SharedConnection::new(configuration.broker.clone()),
+            health: HealthState::new(&[BROKER_COMPONENT], DEFAULT_MAX_HEARTBEAT_AGE),
+            shutdown: Shutdown::new(),
+        });
+
+        let summary = replay(&configuration, recording.as_bytes(), &options, broker_data)
+            .await
+            .unwrap();
+        assert_eq!(summary.published, 2);
+        assert_eq!(summary.invalid, 0);
+    }
+}
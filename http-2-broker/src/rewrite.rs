@@ -0,0 +1,198 @@
+//! Applying "topic_rewrites" to the messages we publish, see "RewriteRule".
+use regex::Regex;
+use serde_json::Value;
+
+use crate::configuration::RewriteRule;
+
+impl std::str::FromStr for RewriteRule {
+    type Err = String;
+
+    /// "FROM=TO", a prefix rule which leaves headers alone, i.e. "organization.facility.system=organization.facility.test"
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once('=') {
+            Some((from, to)) if !from.is_empty() => Ok(Self {
+                prefix: Some(from.to_owned()),
+                pattern: None,
+                replacement: to.to_owned(),
+                headers: false,
+            }),
+            _ => Err(format!("expected FROM=TO, got {:?}", value)),
+        }
+    }
+}
+
+/// How a rule matches
+enum Matcher {
+    /// whole words at the start of the name
+    Prefix(String),
+    /// the whole name, anchored when compiled
+    Pattern(Regex),
+}
+
+struct CompiledRule {
+    matcher: Matcher,
+    replacement: String,
+    headers: bool,
+}
+
+impl CompiledRule {
+    /// The rewritten name, if the rule matches it
+    fn apply(&self, name: &str) -> Option<String> {
+        match &self.matcher {
+            Matcher::Prefix(prefix) => {
+                let rest = name.strip_prefix(prefix.as_str())?;
+                (rest.is_empty() || rest.starts_with('.'))
+                    .then(|| format!("{}{}", self.replacement, rest))
+            }
+            Matcher::Pattern(pattern) => {
+                let captures = pattern.captures(name)?;
+                let mut rewritten = String::new();
+                captures.expand(&self.replacement, &mut rewritten);
+                Some(rewritten)
+            }
+        }
+    }
+}
+
+/// "topic_rewrites", ready to be applied. Without rules, nothing is rewritten.
+#[derive(Default)]
+pub struct TopicRewriter {
+    rules: Vec<CompiledRule>,
+}
+
+impl TopicRewriter {
+    /// Fails if a pattern is not a valid regular expression (validating the configuration catches this first).
+    /// A rule without a prefix or pattern never matches.
+    pub fn new(rules: &[RewriteRule]) -> Result<Self, regex::Error> {
+        let mut compiled = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let matcher = match (&rule.prefix, &rule.pattern) {
+                (Some(prefix), _) => Matcher::Prefix(prefix.clone()),
+                (None, Some(pattern)) => {
+                    Matcher::Pattern(Regex::new(&format!("^(?:{})$", pattern))?)
+                }
+                (None, None) => continue,
+            };
+            compiled.push(CompiledRule {
+                matcher,
+                replacement: rule.replacement.clone(),
+                headers: rule.headers,
+            });
+        }
+        Ok(Self { rules: compiled })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The routing key to publish with, rewritten by the first rule which matches it
+    pub fn rewrite_topic(&self, topic: &str) -> String {
+        self.rules
+            .iter()
+            .find_map(|rule| rule.apply(topic))
+            .unwrap_or_else(|| topic.to_owned())
+    }
+
+    /// A "headers.source" or "headers.destination" value, rewritten by the first rule with "headers" set which matches it
+    pub fn rewrite_header(&self, name: &str) -> String {
+        self.rules
+            .iter()
+            .filter(|rule| rule.headers)
+            .find_map(|rule| rule.apply(name))
+            .unwrap_or_else(|| name.to_owned())
+    }
+
+    /// The routing key and INTERSECT message to publish. The message is only re-serialized if a header was rewritten,
+    /// messages which aren't JSON are left alone.
+    pub fn rewrite(&self, topic: &str, data: String) -> (String, String) {
+        let topic = self.rewrite_topic(topic);
+        if !self.rules.iter().any(|rule| rule.headers) {
+            return (topic, data);
+        }
+        let Ok(mut message) = serde_json::from_str::<Value>(&data) else {
+            return (topic, data);
+        };
+        let mut rewritten = false;
+        if let Some(headers) = message.get_mut("headers").and_then(Value::as_object_mut) {
+            for header in ["source", "destination"] {
+                if let Some(Value::String(name)) = headers.get_mut(header) {
+                    let new_name = self.rewrite_header(name);
+                    if new_name != *name {
+                        *name = new_name;
+                        rewritten = true;
+                    }
+                }
+            }
+        }
+        if !rewritten {
+            return (topic, data);
+        }
+        // serializing a value we just parsed can't fail
+        (topic, serde_json::to_string(&message).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        prefix: Option<&str>,
+        pattern: Option<&str>,
+        replacement: &str,
+        headers: bool,
+    ) -> RewriteRule {
+        RewriteRule {
+            prefix: prefix.map(str::to_owned),
+            pattern: pattern.map(str::to_owned),
+            replacement: replacement.to_owned(),
+            headers,
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rewriter = TopicRewriter::new(&[
+            rule(Some("partner.facility"), None, "organization.partner", true),
+            rule(None, Some(r"(\w+)\.legacy-(\w+)\.(.*)"), "$1.$2.$3", false),
+            rule(Some("partner"), None, "never", false),
+        ])
+        .unwrap();
+        assert_eq!(
+            rewriter.rewrite_topic("partner.facility.system.userspace"),
+            "organization.partner.system.userspace"
+        );
+        assert_eq!(
+            rewriter.rewrite_topic("org.legacy-site.system.events"),
+            "org.site.system.events"
+        );
+        // prefixes only match whole words, patterns the whole routing key
+        assert_eq!(
+            rewriter.rewrite_topic("partner.facilityx"),
+            "never.facilityx"
+        );
+        assert_eq!(
+            rewriter.rewrite_topic("xorg.legacy-site"),
+            "xorg.legacy-site"
+        );
+
+        let (topic, data) = rewriter.rewrite(
+            "org.legacy-site.system.userspace",
+            r#"{"headers": {"source": "partner.facility.system", "destination": "org.legacy-site.system"}, "payload": 1}"#
+                .to_owned(),
+        );
+        assert_eq!(topic, "org.site.system.userspace");
+        let message: Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(message["headers"]["source"], "organization.partner.system");
+        // the pattern rule doesn't apply to headers
+        assert_eq!(message["headers"]["destination"], "org.legacy-site.system");
+        assert_eq!(message["payload"], 1);
+
+        assert_eq!(
+            "a.b=c".parse::<RewriteRule>().unwrap(),
+            rule(Some("a.b"), None, "c", false)
+        );
+        assert!("=c".parse::<RewriteRule>().is_err());
+    }
+}
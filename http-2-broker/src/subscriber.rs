@@ -0,0 +1,526 @@
+/// Subscribing to broker-2-http, and publishing everything it sends us on our own broker.
+use std::sync::Arc;
+use std::time::Duration;
+
+use amqprs::{channel::BasicPublishArguments, connection::Connection, BasicProperties};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use futures::{SinkExt, StreamExt};
+use reqwest_eventsource::{Event, EventSource};
+use secrecy::ExposeSecret;
+use tokio::sync::{mpsc, watch};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{header::AUTHORIZATION, HeaderValue},
+        Message,
+    },
+};
+use tracing::Instrument;
+
+use crate::configuration::{Settings, Transport};
+use crate::metrics::{
+    BATCHES_RECEIVED, BYTES_RECEIVED, CONFIRMATIONS_SENT, CONFIRMATION_FAILURES, EVENTS_RECEIVED,
+    INVALID_EVENTS, MESSAGES_PUBLISHED, PUBLISH_FAILURES, PUBLISH_LATENCY, TOPICS_REWRITTEN,
+};
+use crate::rewrite::TopicRewriter;
+use intersect_ingress_proxy_common::health::{
+    HealthState, BROKER_COMPONENT, HEARTBEAT_INTERVAL, OTHER_PROXY_COMPONENT,
+};
+use intersect_ingress_proxy_common::intersect_messaging::{
+    extract_event_ids, extract_eventsource_batch, extract_eventsource_data_with_metadata,
+    AckRequest, ACCEPT_BATCH_HEADER, ACCEPT_METADATA_HEADER, BATCH_EVENT, CLIENT_EVENT,
+    CONFIRM_HEADER, INTERSECT_MESSAGE_EXCHANGE,
+};
+use intersect_ingress_proxy_common::protocols::amqp::{
+    get_channel, is_routing_key_compliant, make_exchange, to_field_table, SharedConnection,
+};
+use intersect_ingress_proxy_common::protocols::publish_confirm::open_confirmed_channel;
+use intersect_ingress_proxy_common::protocols::websocket::{decode_message, Receipt};
+use intersect_ingress_proxy_common::signals::Shutdown;
+use intersect_ingress_proxy_common::telemetry::{inject_span_context, set_span_parent};
+
+/// Data we need to share across multiple closures.
+pub struct BrokerData {
+    /// may also be used by broker-2-http, if both run in the same process
+    pub connection: Arc<SharedConnection>,
+    pub health: Arc<HealthState>,
+    pub shutdown: Shutdown,
+}
+
+/// Returns false if the message could not be published, but could be if the other proxy sends it again.
+/// Messages we could never publish (i.e. invalid ones) are dropped and count as handled.
+async fn send_message(
+    configuration: &Settings,
+    rewriter: &TopicRewriter,
+    message: String,
+    broker_data: Arc<BrokerData>,
+) -> bool {
+    EVENTS_RECEIVED.inc();
+    BYTES_RECEIVED.inc_by(message.len() as u64);
+    let es_data_result = extract_eventsource_data_with_metadata(&message);
+    if es_data_result.is_err() {
+        INVALID_EVENTS.inc();
+        return true;
+    }
+    let (topic, data, metadata) = es_data_result.unwrap();
+    let (topic, data) = rewrite_message(rewriter, topic, data);
+
+    // continue the trace broker-2-http started (or continued) for this message
+    let span = tracing::info_span!("publish_message", routing_key = %topic);
+    set_span_parent(&span, &metadata);
+    publish_message(configuration, topic, data, broker_data)
+        .instrument(span)
+        .await
+}
+
+/// Apply "topic_rewrites" to a message from the other proxy, before we publish it
+pub fn rewrite_message(rewriter: &TopicRewriter, topic: String, data: String) -> (String, String) {
+    if rewriter.is_empty() {
+        return (topic, data);
+    }
+    let (rewritten_topic, rewritten_data) = rewriter.rewrite(&topic, data);
+    if rewritten_topic != topic {
+        TOPICS_REWRITTEN.inc();
+        tracing::debug!("rewrote topic {} to {}", topic, rewritten_topic);
+    }
+    (rewritten_topic, rewritten_data)
+}
+
+/// Publish every message an event carries, in order: a single message, or all of a batch event.
+/// Returns whether each message was handled (see "send_message"), or nothing if the batch itself is invalid.
+async fn send_event(
+    configuration: &Settings,
+    rewriter: &TopicRewriter,
+    event: &str,
+    data: String,
+    broker_data: Arc<BrokerData>,
+) -> Vec<bool> {
+    if event != BATCH_EVENT {
+        return vec![send_message(configuration, rewriter, data, broker_data).await];
+    }
+    BATCHES_RECEIVED.inc();
+    let Ok(messages) = extract_eventsource_batch(&data) else {
+        INVALID_EVENTS.inc();
+        return vec![];
+    };
+    let mut handled = Vec::with_capacity(messages.len());
+    for message in messages {
+        handled.push(send_message(configuration, rewriter, message, broker_data.clone()).await);
+    }
+    handled
+}
+
+/// Confirm messages to the other proxy until the SSE loop stops sending receipts (or the shutdown deadline passes).
+/// Receipts which arrive while a request is in flight are sent together in the next one.
+async fn confirmation_loop(
+    client: reqwest::Client,
+    configuration: Settings,
+    ack_url: reqwest::Url,
+    client_id: String,
+    mut receipts: mpsc::UnboundedReceiver<Receipt>,
+    shutdown: Shutdown,
+) {
+    let confirm = async {
+        while let Some(receipt) = receipts.recv().await {
+            let mut request = AckRequest {
+                client: client_id.clone(),
+                ..Default::default()
+            };
+            let mut next = Some(receipt);
+            while let Some(receipt) = next {
+                match receipt {
+                    Receipt::Ack(id) => request.ack.push(id),
+                    Receipt::Nack(id) => request.nack.push(id),
+                }
+                next = receipts.try_recv().ok();
+            }
+            let response = client
+                .post(ack_url.clone())
+                .basic_auth(
+                    &configuration.other_proxy.username,
+                    Some(configuration.other_proxy.password.expose_secret()),
+                )
+                .json(&request)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match response {
+                Ok(_) => CONFIRMATIONS_SENT.inc(),
+                Err(e) => {
+                    // the other proxy requeues what we couldn't confirm once its confirmation timeout expires
+                    CONFIRMATION_FAILURES.inc();
+                    tracing::error!(error = ?e, "could not confirm {} messages --- {}", request.ack.len() + request.nack.len(), e);
+                }
+            }
+        }
+    };
+    tokio::select! {
+        _ = confirm => {},
+        _ = shutdown.deadline_passed() => {
+            tracing::warn!("abandoning confirmations we have not sent yet, the other proxy will requeue those messages");
+        },
+    }
+}
+
+/// Publish one INTERSECT message on our broker, waiting for the broker to confirm it if "other_proxy.confirm" is set.
+/// Returns false if publishing failed
+pub async fn publish_message(
+    configuration: &Settings,
+    topic: String,
+    data: String,
+    broker_data: Arc<BrokerData>,
+) -> bool {
+    if !is_routing_key_compliant(&topic) {
+        tracing::warn!(
+            "{} is not a valid AMQP topic name, will not attempt publish",
+            topic
+        );
+        INVALID_EVENTS.inc();
+        return true;
+    }
+    tracing::debug!("Publishing message with topic: {}", &topic);
+
+    let timer = PUBLISH_LATENCY.start_timer();
+    // blocks while reconnecting, if the connection was lost
+    let connection = broker_data.connection.get(0).await;
+    broker_data.health.set_connected(BROKER_COMPONENT, true);
+
+    // TODO - we'd ideally like to potentially reuse the channel instead of closing it every time
+    // see https://github.com/rdoetjes/rabbit_systeminfo/blob/master/systeminfo/src/main.rs#L84 as an example
+    // we NEED to explicitly close the channel, or else problems on the broker may develop
+    let (channel, confirmed) = if configuration.other_proxy.confirm {
+        match open_confirmed_channel(&connection).await {
+            Ok((channel, confirmed)) => (channel, Some(confirmed)),
+            Err(e) => {
+                PUBLISH_FAILURES.inc();
+                tracing::error!(error = ?e, "could not open a channel in confirm mode");
+                return false;
+            }
+        }
+    } else {
+        (get_channel(&connection).await, None)
+    };
+
+    let args = BasicPublishArguments::new(INTERSECT_MESSAGE_EXCHANGE, &topic);
+    // NOTE: the publish() function takes ownership of the string, if you don't care about logging then don't clone
+    let published = match channel
+        .basic_publish(
+            BasicProperties::default()
+                .with_persistence(true)
+                // let INTERSECT services join the trace
+                .with_headers(to_field_table(&inject_span_context(
+                    &tracing::Span::current(),
+                )))
+                .finish(),
+            data.clone().into_bytes(),
+            args,
+        )
+        .await
+    {
+        Ok(_) => match confirmed {
+            None => true,
+            Some(confirmed) => {
+                let timeout = Duration::from_millis(configuration.publish_confirm_timeout_ms);
+                match tokio::time::timeout(timeout, confirmed).await {
+                    Ok(Ok(true)) => true,
+                    Ok(_) => {
+                        tracing::error!("broker did not accept message: {}", data);
+                        false
+                    }
+                    Err(_) => {
+                        tracing::error!(
+                            "broker did not confirm message within {:?}: {}",
+                            timeout,
+                            data
+                        );
+                        false
+                    }
+                }
+            }
+        },
+        Err(e) => {
+            tracing::error!(error = ?e, "could not publish message: {}", data);
+            false
+        }
+    };
+    if published {
+        MESSAGES_PUBLISHED.inc();
+        broker_data.health.record_message();
+        tracing::debug!("message published successfully: {}", data);
+    } else {
+        PUBLISH_FAILURES.inc();
+    }
+    match channel.close().await {
+        Ok(_) => {}
+        Err(e) => {
+            tracing::warn!(error = ?e, "could not close channel");
+        }
+    };
+    timer.observe_duration();
+    published
+}
+
+/// where to confirm messages received over SSE
+fn ack_url(configuration: &Settings) -> Result<reqwest::Url, url::ParseError> {
+    match &configuration.other_proxy.ack_url {
+        Some(url) => reqwest::Url::parse(url),
+        None => reqwest::Url::parse(&configuration.other_proxy.url)?.join("ack"),
+    }
+}
+
+/// Stops without an error once "reconfigured" changes, so we can reconnect with the new settings.
+///
+/// Return value - exit code to use
+pub async fn event_source_loop(
+    configuration: &Settings,
+    rewriter: &TopicRewriter,
+    broker_data: Arc<BrokerData>,
+    reconfigured: &mut watch::Receiver<Settings>,
+) -> i32 {
+    let ack_url = match ack_url(configuration) {
+        Ok(url) => url,
+        Err(e) => {
+            tracing::error!(error = ?e, "invalid URL to confirm messages to");
+            return 1;
+        }
+    };
+    let client = reqwest::Client::new();
+    let mut request = client
+        .get(&configuration.other_proxy.url)
+        .basic_auth(
+            &configuration.other_proxy.username,
+            Some(configuration.other_proxy.password.expose_secret()),
+        )
+        // we continue traces sent along with the messages
+        .header(ACCEPT_METADATA_HEADER, "true");
+    if configuration.other_proxy.accept_batches {
+        request = request.header(ACCEPT_BATCH_HEADER, "true");
+    }
+    if configuration.other_proxy.confirm {
+        request = request.header(CONFIRM_HEADER, "true");
+    }
+    let mut es = EventSource::new(request).unwrap();
+    // set once the other proxy told us our client id, receipts are then sent by "confirmation_loop"
+    let mut receipts: Option<mpsc::UnboundedSender<Receipt>> = None;
+    let health = broker_data.health.clone();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut rc = 0;
+    loop {
+        tokio::select! {
+            _ = heartbeat.tick() => health.heartbeat(),
+            // got data back from web server
+            evt = es.next() => {
+                match evt {
+                    None => {
+                        // probably isn't reachable
+                        tracing::error!("couldn't get next event");
+                        rc = 1;
+                        break;
+                    },
+                    Some(event) => {
+                        match event {
+                            Ok(Event::Open) => {
+                                health.set_connected(OTHER_PROXY_COMPONENT, true);
+                                tracing::info!("connected to {}", &configuration.other_proxy.url);
+                            },
+                            Ok(Event::Message(message)) if message.event == CLIENT_EVENT => {
+                                tracing::info!("confirming messages as client {}", message.data);
+                                let (sender, receiver) = mpsc::unbounded_channel();
+                                broker_data.shutdown.spawn(confirmation_loop(
+                                    client.clone(),
+                                    configuration.clone(),
+                                    ack_url.clone(),
+                                    message.data,
+                                    receiver,
+                                    broker_data.shutdown.clone(),
+                                ));
+                                receipts = Some(sender);
+                            },
+                            Ok(Event::Message(message)) => {
+                                let handled = send_event(configuration, rewriter, &message.event, message.data, broker_data.clone()).await;
+                                if let (Some(receipts), Some(ids)) = (&receipts, extract_event_ids(&message.id)) {
+                                    for (index, id) in ids.into_iter().enumerate() {
+                                        // messages of an invalid batch can never be published, so they count as handled
+                                        let receipt = if handled.get(index).copied().unwrap_or(true) {
+                                            Receipt::Ack(id)
+                                        } else {
+                                            Receipt::Nack(id)
+                                        };
+                                        let _ = receipts.send(receipt);
+                                    }
+                                }
+                            },
+                            Err(err) => {
+                                // will happen if we can't connect to the endpoint OR if the endpoint drops us
+                                health.set_connected(OTHER_PROXY_COMPONENT, false);
+                                tracing::error!(error = ?err, "Event source error --- {}", err);
+                                rc = 1;
+                                break;
+                            },
+                        }
+                    },
+                }
+            },
+            // stop reading new messages, the one we were handling has been published (and confirmed) by now
+            _ = broker_data.shutdown.requested() => {
+                break;
+            },
+            Ok(()) = reconfigured.changed() => {
+                health.set_connected(OTHER_PROXY_COMPONENT, false);
+                break;
+            },
+        };
+    }
+    es.close();
+
+    rc
+}
+
+/// Like "event_source_loop", but over a WebSocket: we confirm every message once we've published it,
+/// so the other proxy only acknowledges it on its broker after that.
+///
+/// Return value - exit code to use
+pub async fn websocket_loop(
+    configuration: &Settings,
+    rewriter: &TopicRewriter,
+    broker_data: Arc<BrokerData>,
+    reconfigured: &mut watch::Receiver<Settings>,
+) -> i32 {
+    let mut request = match configuration.other_proxy.url.as_str().into_client_request() {
+        Ok(request) => request,
+        Err(e) => {
+            tracing::error!(error = ?e, "invalid WebSocket URL {}", &configuration.other_proxy.url);
+            return 1;
+        }
+    };
+    let credentials = BASE64_STANDARD.encode(format!(
+        "{}:{}",
+        configuration.other_proxy.username,
+        configuration.other_proxy.password.expose_secret()
+    ));
+    request.headers_mut().insert(
+        AUTHORIZATION,
+        format!("Basic {}", credentials).try_into().unwrap(),
+    );
+    request
+        .headers_mut()
+        .insert(ACCEPT_METADATA_HEADER, HeaderValue::from_static("true"));
+
+    let health = broker_data.health.clone();
+    let mut socket = match connect_async(request).await {
+        Ok((socket, _)) => socket,
+        Err(e) => {
+            tracing::error!(error = ?e, "could not connect to {}", &configuration.other_proxy.url);
+            return 1;
+        }
+    };
+    health.set_connected(OTHER_PROXY_COMPONENT, true);
+    tracing::info!("connected to {}", &configuration.other_proxy.url);
+
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut rc = 0;
+    loop {
+        tokio::select! {
+            _ = heartbeat.tick() => health.heartbeat(),
+            frame = socket.next() => {
+                let receipt = match frame {
+                    Some(Ok(Message::Binary(frame))) => match decode_message(&frame) {
+                        Ok((id, data)) => {
+                            if send_message(configuration, rewriter, data.to_owned(), broker_data.clone()).await {
+                                Receipt::Ack(id)
+                            } else {
+                                Receipt::Nack(id)
+                            }
+                        },
+                        Err(_) => {
+                            // without an id we can't answer, the other proxy requeues it once we disconnect
+                            INVALID_EVENTS.inc();
+                            continue;
+                        },
+                    },
+                    // pings are answered for us, and nothing else is expected
+                    Some(Ok(Message::Close(_))) | None => {
+                        health.set_connected(OTHER_PROXY_COMPONENT, false);
+                        tracing::error!("{} closed the connection", &configuration.other_proxy.url);
+                        rc = 1;
+                        break;
+                    },
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        health.set_connected(OTHER_PROXY_COMPONENT, false);
+                        tracing::error!(error = ?e, "WebSocket error --- {}", e);
+                        rc = 1;
+                        break;
+                    },
+                };
+                // serializing a receipt can't fail
+                let receipt = serde_json::to_string(&receipt).unwrap();
+                if let Err(e) = socket.send(Message::Text(receipt)).await {
+                    health.set_connected(OTHER_PROXY_COMPONENT, false);
+                    tracing::error!(error = ?e, "could not confirm message --- {}", e);
+                    rc = 1;
+                    break;
+                }
+            },
+            // stop reading new messages, the one we were handling has been published (and confirmed) by now
+            _ = broker_data.shutdown.requested() => {
+                break;
+            },
+            Ok(()) = reconfigured.changed() => {
+                health.set_connected(OTHER_PROXY_COMPONENT, false);
+                break;
+            },
+        };
+    }
+    let _ = socket.close(None).await;
+
+    rc
+}
+
+/// Receive messages from the other proxy over the configured transport until it disconnects or we are told to shut down.
+/// Whenever "configuration" changes (i.e. "other_proxy" or "topic_rewrites" was reloaded), we disconnect and connect again with the new settings.
+///
+/// Return value - exit code to use
+pub async fn subscribe_loop(
+    mut configuration: watch::Receiver<Settings>,
+    broker_data: Arc<BrokerData>,
+) -> i32 {
+    loop {
+        let current = configuration.borrow_and_update().clone();
+        let rewriter = match TopicRewriter::new(&current.topic_rewrites) {
+            Ok(rewriter) => rewriter,
+            Err(e) => {
+                tracing::error!(error = ?e, "invalid topic_rewrites pattern");
+                return 1;
+            }
+        };
+        let rc = match current.other_proxy.transport {
+            Transport::Sse => {
+                event_source_loop(&current, &rewriter, broker_data.clone(), &mut configuration)
+                    .await
+            }
+            Transport::Websocket => {
+                websocket_loop(&current, &rewriter, broker_data.clone(), &mut configuration).await
+            }
+        };
+        if rc != 0 || broker_data.shutdown.is_requested() {
+            return rc;
+        }
+        tracing::info!(
+            "disconnected from {} to apply the reloaded configuration",
+            &current.other_proxy.url
+        );
+    }
+}
+
+/// Declare the INTERSECT exchange on the broker, in case nobody else has yet.
+/// Do this once at startup, not on every message.
+pub async fn declare_exchange(connection: &Connection) -> Result<(), amqprs::error::Error> {
+    let channel = get_channel(connection).await;
+    let exchange_result = make_exchange(&channel).await;
+    if let Err(e) = channel.close().await {
+        tracing::warn!(error = ?e, "could not close channel after making exchange");
+    }
+    exchange_result
+}
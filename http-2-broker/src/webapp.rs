@@ -0,0 +1,71 @@
+use axum::{routing::get, serve::Serve, Router};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::configuration::Settings;
+
+use intersect_ingress_proxy_common::{
+    health::{livez_handler, readyz_handler, HealthState},
+    metrics::metrics_handler,
+    signals::Shutdown,
+};
+
+type WebAppServer = Serve<Router, Router>;
+
+/// http-2-broker does not serve any INTERSECT data over HTTP, this server only exists for operational endpoints.
+pub struct WebApplication {
+    pub port: u16,
+    pub server: WebAppServer,
+    shutdown: Shutdown,
+}
+
+impl WebApplication {
+    pub async fn build(
+        configuration: &Settings,
+        health: Arc<HealthState>,
+        shutdown: Shutdown,
+    ) -> Result<Self, anyhow::Error> {
+        let address = format!(
+            "{}:{}",
+            if configuration.production {
+                "0.0.0.0"
+            } else {
+                "127.0.0.1"
+            },
+            configuration.app_port
+        );
+        let listener = TcpListener::bind(address).await?;
+        let port = listener.local_addr().unwrap().port();
+        let server = run(listener, health);
+
+        tracing::info!("Health and metrics server is running on port {}", port);
+
+        Ok(Self {
+            port,
+            server,
+            shutdown,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        // the return type of "with_graceful_shutdown" is unstable, so set it up here
+        let shutdown = self.shutdown;
+        self.server
+            .with_graceful_shutdown(async move { shutdown.requested().await })
+            .await
+    }
+}
+
+fn run(listener: TcpListener, health: Arc<HealthState>) -> WebAppServer {
+    let app = Router::new()
+        .route("/livez", get(livez_handler))
+        .route("/readyz", get(readyz_handler))
+        .with_state(health)
+        .route("/metrics", get(metrics_handler));
+
+    axum::serve(listener, app)
+}
@@ -0,0 +1,101 @@
+/// FOR DEVOPS USERS:
+/// 1) ingress-proxy runs broker-2-http and http-2-broker in one process, against the same broker
+/// 2) it is configured with a single tree: every broker-2-http setting, plus http-2-broker's "other_proxy" (and optionally "publish_confirm_timeout_ms" and "topic_rewrites")
+/// 3) each half reads its own settings from that tree, so shared settings ("broker", "app_port", "log_level", "production", "otlp_endpoint") are given once
+/// 4) "app_port" serves subscribers, as well as health checks and metrics for both halves
+/// 5) see broker-2-http/src/configuration.rs and http-2-broker/src/configuration.rs for what each setting does
+use intersect_ingress_proxy_common::cli::Cli;
+use intersect_ingress_proxy_common::configuration::{ConfigProblems, Validate};
+use intersect_ingress_proxy_common::reload::Reload;
+
+/// Printed (and compared when reloading) as the single tree it is read from, shared settings are the same in both halves.
+#[derive(serde::Serialize, Clone)]
+pub struct Settings {
+    /// the half sending our broker's messages to the other System
+    #[serde(flatten)]
+    pub broker_2_http: broker_2_http::configuration::Settings,
+    /// the half publishing the other System's messages on our broker
+    #[serde(flatten)]
+    pub http_2_broker: http_2_broker::configuration::Settings,
+}
+
+impl Settings {
+    /// Read both halves' settings from the same sources, see "Cli::read_configuration"
+    pub fn read(cli: &Cli) -> Result<Self, config::ConfigError> {
+        Ok(Self {
+            broker_2_http: cli.read_configuration()?,
+            http_2_broker: cli.read_configuration()?,
+        })
+    }
+}
+
+impl Validate for Settings {
+    /// both halves read the same tree, so shared settings are checked at the same paths (and reported once)
+    fn validate(&self, path: &str, problems: &mut ConfigProblems) {
+        self.broker_2_http.validate(path, problems);
+        self.http_2_broker.validate(path, problems);
+    }
+}
+
+impl Reload for Settings {
+    /// what either half can reload, see their "Reload" implementations
+    const RELOADABLE: &'static [&'static str] = &[
+        "log_level",
+        "username",
+        "password",
+        "admin.username",
+        "admin.password",
+        "queue.binding_keys",
+        "batching",
+        "confirmation_timeout_ms",
+        "other_proxy",
+        "topic_rewrites",
+    ];
+
+    fn changed_secrets(&self, new: &Self, path: &str, changed: &mut Vec<String>) {
+        self.broker_2_http
+            .changed_secrets(&new.broker_2_http, path, changed);
+        self.http_2_broker
+            .changed_secrets(&new.http_2_broker, path, changed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_configuration_configures_both_halves() {
+        let file = concat!(env!("CARGO_MANIFEST_DIR"), "/conf.yaml");
+        let config = config::Config::builder()
+            .add_source(config::File::new(file, config::FileFormat::Yaml))
+            .build()
+            .unwrap();
+        let settings = Settings {
+            broker_2_http: config.clone().try_deserialize().unwrap(),
+            http_2_broker: config.try_deserialize().unwrap(),
+        };
+        assert_eq!(
+            settings.broker_2_http.app_port,
+            settings.http_2_broker.app_port
+        );
+        assert_eq!(
+            settings.broker_2_http.broker.port,
+            settings.http_2_broker.broker.port
+        );
+        let mut problems = ConfigProblems::new();
+        settings.validate("", &mut problems);
+        assert!(problems.is_empty(), "{}", problems);
+    }
+
+    #[test]
+    fn everything_either_half_reloads_can_be_reloaded() {
+        let halves = [
+            <broker_2_http::configuration::Settings as Reload>::RELOADABLE,
+            <http_2_broker::configuration::Settings as Reload>::RELOADABLE,
+        ];
+        for path in halves.concat() {
+            assert!(Settings::RELOADABLE.contains(&path), "{}", path);
+        }
+    }
+}
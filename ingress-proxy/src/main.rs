@@ -0,0 +1,156 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use broker_2_http::{
+    amqp_consumer::{broker_consumer_loop, ConsumerControl, ConsumerTopology},
+    broadcaster::Broadcaster,
+    webapp::WebApplication,
+};
+use http_2_broker::subscriber::{declare_exchange, subscribe_loop, BrokerData};
+use ingress_proxy::configuration::Settings;
+use intersect_ingress_proxy_common::cli::Cli;
+use intersect_ingress_proxy_common::configuration::configuration_or_exit;
+
+use intersect_ingress_proxy_common::health::{
+    HealthState, BROKER_COMPONENT, DEFAULT_MAX_HEARTBEAT_AGE, OTHER_PROXY_COMPONENT,
+};
+use intersect_ingress_proxy_common::protocols::amqp::SharedConnection;
+use intersect_ingress_proxy_common::reload::{is_reloadable, reload_on_change};
+use intersect_ingress_proxy_common::signals::Shutdown;
+use intersect_ingress_proxy_common::telemetry::{
+    get_json_subscriber, get_pretty_subscriber, init_subscriber, OtlpTracing,
+};
+use tokio::sync::watch;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse_for(
+        "ingress-proxy",
+        env!("CARGO_PKG_VERSION"),
+        include_str!("../conf.yaml"),
+    );
+    let configuration = configuration_or_exit(&cli, Settings::read);
+    // everything both halves share is read from here
+    let common = &configuration.broker_2_http;
+
+    let otlp = common.otlp_endpoint.as_ref().map(|endpoint| {
+        OtlpTracing::init("ingress-proxy", endpoint).expect("Failed to configure OTLP exporter")
+    });
+
+    // Start logging
+    let log_level = if common.production {
+        let (subscriber, log_level) = get_json_subscriber(
+            "ingress-proxy".into(),
+            common.log_level.to_string(),
+            std::io::stderr,
+            otlp.as_ref(),
+        );
+        init_subscriber(subscriber);
+        log_level
+    } else {
+        let (subscriber, log_level) =
+            get_pretty_subscriber(common.log_level.to_string(), otlp.as_ref());
+        init_subscriber(subscriber);
+        log_level
+    };
+    let topology = ConsumerTopology::new(common)?;
+
+    let shutdown = Shutdown::new();
+    shutdown.request_on_os_signal();
+
+    let broadcaster = Broadcaster::new(&common.subscriber_queue, shutdown.clone());
+    let health = HealthState::new(
+        &[BROKER_COMPONENT, OTHER_PROXY_COMPONENT],
+        DEFAULT_MAX_HEARTBEAT_AGE,
+    );
+
+    let consumer_control = ConsumerControl::new();
+
+    // broker-2-http's server also serves health checks and metrics for http-2-broker
+    let application = WebApplication::build(
+        common,
+        broadcaster.clone(),
+        health.clone(),
+        consumer_control.clone(),
+        log_level.clone(),
+        shutdown.clone(),
+    )
+    .await?;
+    let app_state = application.state();
+    let server_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        if let Err(e) = application.run_until_stopped().await {
+            tracing::error!(error = ?e, "web server failed");
+            server_shutdown.request();
+        }
+    });
+
+    // SIGHUP / SIGUSR1 or changing the configuration file applies what both halves can reload
+    let (reloaded, reloaded_rx) = watch::channel(configuration.http_2_broker.clone());
+    let binding_keys = topology.binding_keys();
+    tokio::spawn(reload_on_change(
+        cli,
+        configuration.clone(),
+        Settings::read,
+        move |new: &Settings, changed: &[String]| {
+            app_state.reload(&new.broker_2_http);
+            binding_keys.reload(&new.broker_2_http);
+            if changed.iter().any(|path| path == "log_level") {
+                if let Err(e) = log_level.set_level(&new.broker_2_http.log_level) {
+                    tracing::error!(error = ?e, "could not change log level");
+                }
+            }
+            reloaded.send_if_modified(|current| {
+                *current = new.http_2_broker.clone();
+                changed
+                    .iter()
+                    .any(|path| is_reloadable(&["other_proxy", "topic_rewrites"], path))
+            });
+        },
+    ));
+
+    let connection = SharedConnection::new(common.broker.clone());
+
+    // try to declare the exchange on the broker before publishing to it, fail if not
+    if let Err(err) = declare_exchange(&connection.get(10).await).await {
+        tracing::error!("could not create exchange: {}", err);
+        connection.close().await;
+        std::process::exit(1);
+    }
+
+    broker_consumer_loop(
+        connection.clone(),
+        common.topic_prefix.clone(),
+        topology,
+        broadcaster.clone(),
+        health.clone(),
+        consumer_control,
+        shutdown.clone(),
+    );
+
+    let broker_data = Arc::new(BrokerData {
+        connection: connection.clone(),
+        health,
+        shutdown: shutdown.clone(),
+    });
+    // returns once shutdown is requested, or once we lose the other proxy
+    let rc = subscribe_loop(reloaded_rx, broker_data).await;
+    shutdown.request();
+
+    tracing::warn!("Application shutting down, please wait for cleanups...");
+    let abandoned = shutdown
+        .wait(Duration::from_millis(common.shutdown_timeout_ms))
+        .await;
+    if abandoned > 0 {
+        tracing::error!(
+            "{} tasks did not shut down in time, whatever they had not finished was abandoned",
+            abandoned
+        );
+    }
+    connection.close().await;
+    if let Some(otlp) = otlp {
+        otlp.shutdown();
+    }
+    tracing::info!("process gracefully shutdown");
+    std::process::exit(rc);
+}
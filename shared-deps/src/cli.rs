@@ -0,0 +1,114 @@
+//! Command line options shared by every application.
+//!
+//! Settings are layered, each overriding the one before:
+//! defaults < configuration file < PROXYAPP_* environment variables < command line
+use std::path::PathBuf;
+
+use clap::{CommandFactory, FromArgMatches, Parser};
+
+/// Command line options every application takes, see "Cli::parse_for"
+#[derive(Parser, Clone, Debug, Default)]
+#[command(
+    about = "Proxies INTERSECT messages between Systems. Settings come from (highest precedence first) the command line, PROXYAPP_* environment variables, the configuration file and defaults."
+)]
+pub struct Cli {
+    /// YAML configuration file
+    #[arg(short, long, env = "APP_CONFIG_FILE", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Override any setting, i.e. "--set broker.port=5672" (can be repeated)
+    #[arg(long = "set", value_name = "SETTING=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<(String, String)>,
+    /// Same as "--set log_level=..."
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    /// Same as "--set app_port=..."
+    #[arg(long, value_name = "PORT")]
+    pub app_port: Option<u16>,
+    /// Same as "--set broker.host=..."
+    #[arg(long, value_name = "HOST")]
+    pub broker_host: Option<String>,
+    /// Same as "--set broker.port=..."
+    #[arg(long, value_name = "PORT")]
+    pub broker_port: Option<u16>,
+    /// Same as "--set production=true"
+    #[arg(long)]
+    pub production: bool,
+    /// Print the configuration which would be used (secrets redacted) and check it, then exit
+    #[arg(long)]
+    pub check_config: bool,
+    /// Print an example configuration file with every setting, then exit
+    #[arg(long)]
+    pub print_default_config: bool,
+    /// printed by "--print-default-config"
+    #[arg(skip)]
+    pub default_config: &'static str,
+}
+
+fn parse_override(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((setting, value)) if !setting.is_empty() => Ok((setting.to_owned(), value.to_owned())),
+        _ => Err(format!("expected SETTING=VALUE, got {:?}", value)),
+    }
+}
+
+impl Cli {
+    /// Parse the command line of the application called "name", exiting on "--help", "--version" or bad arguments.
+    /// "default_config" is the example configuration for "--print-default-config".
+    pub fn parse_for(
+        name: &'static str,
+        version: &'static str,
+        default_config: &'static str,
+    ) -> Self {
+        let matches = Self::command().name(name).version(version).get_matches();
+        let mut cli = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        cli.default_config = default_config;
+        cli
+    }
+
+    /// Every setting given on the command line, as (setting, value). Later ones win.
+    pub fn settings(&self) -> Vec<(String, String)> {
+        let shortcuts = [
+            ("log_level", self.log_level.clone()),
+            ("app_port", self.app_port.map(|port| port.to_string())),
+            ("broker.host", self.broker_host.clone()),
+            ("broker.port", self.broker_port.map(|port| port.to_string())),
+            ("production", self.production.then(|| "true".to_owned())),
+        ];
+        shortcuts
+            .into_iter()
+            .filter_map(|(setting, value)| Some((setting.to_owned(), value?)))
+            .chain(self.overrides.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_come_after_shortcuts() {
+        let cli = Cli::try_parse_from([
+            "proxy",
+            "--config",
+            "conf.yaml",
+            "--app-port",
+            "9000",
+            "--set",
+            "app_port=9001",
+            "--set",
+            "broker.password=a=b",
+        ])
+        .unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("conf.yaml")));
+        assert_eq!(
+            cli.settings(),
+            [
+                ("app_port".to_owned(), "9000".to_owned()),
+                ("app_port".to_owned(), "9001".to_owned()),
+                ("broker.password".to_owned(), "a=b".to_owned()),
+            ]
+        );
+        assert!(Cli::try_parse_from(["proxy", "--set", "app_port"]).is_err());
+    }
+}
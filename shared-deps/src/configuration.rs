@@ -1,16 +1,30 @@
 /// FOR DEVOPS USERS:
 /// This file represents common configuration structures used across both applications.
-/// Implementation details are in the "get_configuration" function.
+/// Implementation details are in "Cli::read_configuration", validation is done with the "Validate" trait.
+/// Run any application with "--check-config" to print the configuration it would use, and every problem with it.
+/// Any setting can be read from a file instead (i.e. a mounted Kubernetes Secret), see "SETTING_FILE_SUFFIX".
 use std::{fmt::Display, str::FromStr};
 
 use secrecy::Secret;
+use serde::{Serialize, Serializer};
 use serde_aux::field_attributes::deserialize_number_from_string;
 
-#[derive(serde::Deserialize, Clone)]
+use crate::cli::Cli;
+
+/// shown instead of secrets when printing the configuration
+pub const REDACTED: &str = "[REDACTED]";
+
+/// "broker.password_file: /run/secrets/broker-password" sets "broker.password" to the contents of that file,
+/// and so does "PROXYAPP_BROKER__PASSWORD_FILE", so secrets never have to be in the environment.
+/// If both are set, the file wins. Files are read again whenever the configuration is reloaded.
+pub const SETTING_FILE_SUFFIX: &str = "_file";
+
+#[derive(serde::Deserialize, Serialize, Clone)]
 pub struct BrokerSettings {
     /// broker username
     pub username: String,
     /// broker password
+    #[serde(serialize_with = "serialize_redacted")]
     pub password: Secret<String>,
     #[serde(deserialize_with = "deserialize_number_from_string")]
     /// broker port
@@ -19,7 +33,9 @@ pub struct BrokerSettings {
     pub host: String,
 }
 
-#[derive(serde::Deserialize, Clone)]
+/// Read from (and printed as) the strings "FromStr" and "Display" use.
+#[derive(serde::Deserialize, Serialize, Clone)]
+#[serde(try_from = "String", into = "String")]
 pub enum LogLevel {
     Trace,
     Debug,
@@ -36,56 +52,419 @@ impl FromStr for LogLevel {
             "trace" => Ok(LogLevel::Trace),
             "debug" => Ok(LogLevel::Debug),
             "warning" | "warn" => Ok(LogLevel::Warning),
+            "info" => Ok(LogLevel::Info),
             "error" => Ok(LogLevel::Error),
-            // default to info
-            _ => Ok(LogLevel::Info),
+            _ => Err("unknown log level, use one of trace, debug, info, warn or error"),
         }
     }
 }
 
+impl TryFrom<String> for LogLevel {
+    type Error = &'static str;
+
+    fn try_from(value: String) -> Result<Self, &'static str> {
+        value.parse()
+    }
+}
+
+impl From<LogLevel> for String {
+    fn from(value: LogLevel) -> Self {
+        value.to_string()
+    }
+}
+
+/// The displayed value is a valid tracing directive (note that "warning" is not).
 impl Display for LogLevel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             LogLevel::Debug => f.write_str("debug"),
             LogLevel::Error => f.write_str("error"),
-            LogLevel::Warning => f.write_str("warning"),
+            LogLevel::Warning => f.write_str("warn"),
             LogLevel::Info => f.write_str("info"),
             LogLevel::Trace => f.write_str("trace"),
         }
     }
 }
 
-/// Common logic for obtaining a configuration of type T
+/// One thing wrong with the configuration. "path" is the setting it's about, i.e. "other_proxy.url" (PROXYAPP_OTHER_PROXY__URL).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    pub path: String,
+    pub message: String,
+}
+
+/// Every problem found in a configuration, so they can all be fixed at once
+#[derive(Debug, Default)]
+pub struct ConfigProblems {
+    problems: Vec<ConfigProblem>,
+}
+
+/// where a setting called "name" is, inside the settings at "path"
+pub fn setting_path(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+impl ConfigProblems {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a problem with the setting at "path". The same problem is only recorded once.
+    pub fn add(&mut self, path: &str, message: impl Into<String>) {
+        let problem = ConfigProblem {
+            path: path.to_owned(),
+            message: message.into(),
+        };
+        if !self.problems.contains(&problem) {
+            self.problems.push(problem);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ConfigProblem> {
+        self.problems.iter()
+    }
+
+    pub fn check_not_empty(&mut self, path: &str, value: &str) {
+        if value.trim().is_empty() {
+            self.add(path, "must not be empty");
+        }
+    }
+
+    pub fn check_port(&mut self, path: &str, port: u16) {
+        if port == 0 {
+            self.add(path, "must be a port number between 1 and 65535");
+        }
+    }
+
+    pub fn check_positive(&mut self, path: &str, value: u64) {
+        if value == 0 {
+            self.add(path, "must be greater than 0");
+        }
+    }
+
+    /// "value" must be an absolute URL using one of "schemes"
+    pub fn check_url(&mut self, path: &str, value: &str, schemes: &[&str]) {
+        match url::Url::parse(value) {
+            Ok(url) if schemes.contains(&url.scheme()) && url.has_host() => {}
+            Ok(_) => self.add(
+                path,
+                format!("must be a URL starting with {}://", schemes.join(":// or ")),
+            ),
+            Err(e) => self.add(path, format!("is not a valid URL ({})", e)),
+        }
+    }
+}
+
+impl Display for ConfigProblems {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}: {}", problem.path, problem.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigProblems {}
+
+/// Checks for settings which deserialize fine, but can't work (i.e. an empty topic prefix or a port of 0)
+pub trait Validate {
+    /// Add every problem with these settings to "problems". "path" is where these settings are in the configuration, empty for the root.
+    fn validate(&self, path: &str, problems: &mut ConfigProblems);
+}
+
+impl Validate for BrokerSettings {
+    fn validate(&self, path: &str, problems: &mut ConfigProblems) {
+        problems.check_not_empty(&setting_path(path, "host"), &self.host);
+        problems.check_port(&setting_path(path, "port"), self.port);
+        problems.check_not_empty(&setting_path(path, "username"), &self.username);
+    }
+}
+
+/// "serialize_with" for secrets, so they never show up when printing the configuration
+pub fn serialize_redacted<S: Serializer>(
+    _secret: &Secret<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+/// Everything which can go wrong getting the configuration
+#[derive(Debug)]
+pub enum ConfigurationError {
+    /// settings are missing, or can't be read as the right type
+    Read(config::ConfigError),
+    /// settings were read, but some of them can't work
+    Invalid(ConfigProblems),
+}
+
+impl Display for ConfigurationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigurationError::Read(e) => write!(f, "could not read configuration: {}", e),
+            ConfigurationError::Invalid(problems) => {
+                write!(f, "invalid configuration:\n{}", problems)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigurationError {}
+
+/// Read the configuration with "read" (usually "Cli::read_configuration"), then validate it
+pub fn get_valid_configuration<T: Validate>(
+    read: impl FnOnce() -> Result<T, config::ConfigError>,
+) -> Result<T, ConfigurationError> {
+    let configuration = read().map_err(ConfigurationError::Read)?;
+    let mut problems = ConfigProblems::new();
+    configuration.validate("", &mut problems);
+    if problems.is_empty() {
+        Ok(configuration)
+    } else {
+        Err(ConfigurationError::Invalid(problems))
+    }
+}
+
+/// Read and validate the configuration at startup with "read" (usually "Cli::read_configuration"),
+/// exiting with every problem found if there are any.
+/// Logging isn't set up yet at this point, so problems are printed to stderr.
 ///
-/// Rules:
-/// - highest priority comes from environment variables
-/// - lowest priority comes from values in the YAML config file (path determined in APP_CONFIG_FILE environment variable)
-/// - environment variables can be set for nested structs, i.e. 'Settings.broker.port' is set by 'PROXYAPP_BROKER__PORT=5001' . Two underscores separate struct values.
-/// - while you are not required to provide a single source of configuration and can mix them as you want, note that failure to provide a config value
-///   will cause the application to exit with a non-zero code.
-pub fn get_configuration<'de, T: serde::Deserialize<'de>>() -> Result<T, config::ConfigError> {
-    let file_config = match std::env::var("APP_CONFIG_FILE") {
-        Ok(config_file_path) => config::Config::builder()
-            .add_source(config::File::new(
-                &config_file_path,
-                config::FileFormat::Yaml,
-            ))
-            .build()?,
-        Err(_) => config::Config::builder().build()?,
+/// With "--check-config", print the configuration (secrets redacted) to stdout instead,
+/// then exit with 0 if it is valid and 1 if not. "--print-default-config" just prints the example configuration.
+pub fn configuration_or_exit<T: Validate + Serialize>(
+    cli: &Cli,
+    read: impl FnOnce(&Cli) -> Result<T, config::ConfigError>,
+) -> T {
+    if cli.print_default_config {
+        print!("{}", cli.default_config);
+        std::process::exit(0);
+    }
+    let configuration = match read(cli) {
+        Ok(configuration) => configuration,
+        Err(e) => {
+            eprintln!("{}", ConfigurationError::Read(e));
+            std::process::exit(1);
+        }
     };
+    let mut problems = ConfigProblems::new();
+    configuration.validate("", &mut problems);
+    if cli.check_config {
+        // serializing our own settings can't fail, going through a Value merges settings which were flattened from the same tree
+        let printed = serde_json::to_value(&configuration).unwrap();
+        println!("{}", serde_json::to_string_pretty(&printed).unwrap());
+    }
+    if !problems.is_empty() {
+        eprintln!("{}", ConfigurationError::Invalid(problems));
+        std::process::exit(1);
+    }
+    if cli.check_config {
+        eprintln!("configuration is valid");
+        std::process::exit(0);
+    }
+    configuration
+}
 
-    let settings = config::Config::builder()
-        .set_default("production", false)?
-        .set_default("log_level", "info")?
-        .add_source(file_config)
+impl Cli {
+    /// Common logic for obtaining a configuration of type T
+    ///
+    /// Rules:
+    /// - highest priority comes from the command line, i.e. "--set broker.port=5001" or "--broker-port 5001"
+    /// - then environment variables
+    /// - lowest priority comes from values in the YAML config file ("--config", or the APP_CONFIG_FILE environment variable)
+    /// - environment variables can be set for nested structs, i.e. 'Settings.broker.port' is set by 'PROXYAPP_BROKER__PORT=5001' . Two underscores separate struct values.
+    /// - while you are not required to provide a single source of configuration and can mix them as you want, note that failure to provide a config value
+    ///   will cause the application to exit with a non-zero code.
+    /// - any setting "x" can instead be read from the file named by "x_file", see "SETTING_FILE_SUFFIX".
+    ///   This happens within each of the above, so "--set broker.password=..." still beats a "broker.password_file" in the YAML file.
+    pub fn read_configuration<'de, T: serde::Deserialize<'de>>(
+        &self,
+    ) -> Result<T, config::ConfigError> {
+        let file_config = match &self.config {
+            Some(config_file_path) => config::Config::builder()
+                .add_source(
+                    config::File::from(config_file_path.as_path()).format(config::FileFormat::Yaml),
+                )
+                .build()?,
+            None => config::Config::builder().build()?,
+        };
         // Add in settings from environment variables (with a prefix of PROXYAPP and '__' as separator)
         // E.g. `PROXYAPP_BROKER__PORT=5001 would set `Settings.broker.port`
-        .add_source(
-            config::Environment::with_prefix("PROXYAPP")
-                .prefix_separator("_")
-                .separator("__"),
+        let env_config = config::Config::builder()
+            .add_source(
+                config::Environment::with_prefix("PROXYAPP")
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .build()?;
+        let mut cli_config = config::Config::builder();
+        for (setting, value) in self.settings() {
+            cli_config = cli_config.set_override(setting, value)?;
+        }
+
+        config::Config::builder()
+            .set_default("production", false)?
+            .set_default("log_level", "info")?
+            .add_source(read_setting_files(file_config)?)
+            .add_source(read_setting_files(env_config)?)
+            .add_source(read_setting_files(cli_config.build()?)?)
+            .build()?
+            .try_deserialize::<T>()
+    }
+}
+
+/// Replace every "x_file" setting with an "x" setting holding the contents of that file.
+/// Call this on each source separately, so sources with a higher priority still override the file.
+fn read_setting_files(mut settings: config::Config) -> Result<config::Config, config::ConfigError> {
+    if let config::ValueKind::Table(table) = &mut settings.cache.kind {
+        read_setting_files_in("", table)?;
+    }
+    Ok(settings)
+}
+
+fn read_setting_files_in(
+    path: &str,
+    table: &mut config::Map<String, config::Value>,
+) -> Result<(), config::ConfigError> {
+    let file_keys: Vec<String> = table
+        .keys()
+        .filter(|key| key.ends_with(SETTING_FILE_SUFFIX))
+        .cloned()
+        .collect();
+    for file_key in file_keys {
+        let file_setting = setting_path(path, &file_key);
+        let file = table.remove(&file_key).unwrap().into_string()?;
+        let contents = std::fs::read_to_string(&file).map_err(|e| {
+            config::ConfigError::Message(format!(
+                "{}: could not read {}: {}",
+                file_setting, file, e
+            ))
+        })?;
+        // files usually end with a newline, which is never part of the secret
+        let contents = contents.trim_end_matches(['\n', '\r']).to_owned();
+        let key = file_key.trim_end_matches(SETTING_FILE_SUFFIX).to_owned();
+        table.insert(key, config::Value::new(Some(&file), contents));
+    }
+    for (key, value) in table.iter_mut() {
+        if let config::ValueKind::Table(nested) = &mut value.kind {
+            read_setting_files_in(&setting_path(path, key), nested)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn log_levels_are_parsed_strictly() {
+        assert!(matches!("WARN".parse(), Ok(LogLevel::Warning)));
+        assert!(matches!("info".parse(), Ok(LogLevel::Info)));
+        assert!("verbose".parse::<LogLevel>().is_err());
+    }
+
+    #[test]
+    fn settings_are_read_from_files() {
+        let file = std::env::temp_dir().join(format!("broker-password-{}", std::process::id()));
+        std::fs::write(&file, "from_file\n").unwrap();
+        let yaml = format!(
+            "broker: {{ username: user, password: inline, password_file: {:?}, host: localhost, port: 5672 }}",
+            file
+        );
+        let settings = config::Config::builder()
+            .add_source(config::File::from_str(&yaml, config::FileFormat::Yaml))
+            .build()
+            .unwrap();
+        let broker: BrokerSettings = read_setting_files(settings).unwrap().get("broker").unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(broker.password.expose_secret(), "from_file");
+    }
+
+    #[test]
+    fn setting_files_only_override_their_own_source() {
+        #[derive(serde::Deserialize)]
+        struct Settings {
+            broker: BrokerSettings,
+        }
+        let directory = std::env::temp_dir().join(format!("setting-files-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let password_file = directory.join("password");
+        std::fs::write(&password_file, "from_file\n").unwrap();
+        let config_file = directory.join("conf.yaml");
+        std::fs::write(
+            &config_file,
+            format!(
+                "broker: {{ username: user, password: inline, password_file: {:?}, host: localhost, port: 5672 }}",
+                password_file
+            ),
+        )
+        .unwrap();
+        let read = |overrides: &[(&str, String)]| -> String {
+            let cli = Cli {
+                config: Some(config_file.clone()),
+                overrides: overrides
+                    .iter()
+                    .map(|(setting, value)| (setting.to_string(), value.clone()))
+                    .collect(),
+                ..Cli::default()
+            };
+            let settings: Settings = cli.read_configuration().unwrap();
+            settings.broker.password.expose_secret().clone()
+        };
+
+        // within the YAML file, the file wins
+        assert_eq!(read(&[]), "from_file");
+        // the command line beats the YAML file's "password_file"
+        assert_eq!(
+            read(&[("broker.password", "from_cli".to_owned())]),
+            "from_cli"
+        );
+        std::fs::write(
+            &config_file,
+            "broker: { username: user, password: inline, host: localhost, port: 5672 }",
         )
-        .build()?;
+        .unwrap();
+        assert_eq!(
+            read(&[(
+                "broker.password_file",
+                password_file.to_string_lossy().into_owned()
+            )]),
+            "from_file"
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn every_problem_is_reported_with_its_path() {
+        let broker = BrokerSettings {
+            username: "".into(),
+            password: Secret::new("password".into()),
+            port: 0,
+            host: "localhost".into(),
+        };
+        let mut problems = ConfigProblems::new();
+        broker.validate("broker", &mut problems);
+        problems.check_url(
+            "other_proxy.url",
+            "localhost:8080/subscribe",
+            &["http", "https"],
+        );
+        problems.check_url(
+            "other_proxy.url",
+            "https://example.com/subscribe",
+            &["http", "https"],
+        );
 
-    settings.try_deserialize::<T>()
+        let paths: Vec<&str> = problems.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(paths, ["broker.port", "broker.username", "other_proxy.url"]);
+        assert_eq!(serde_json::to_value(&broker).unwrap()["password"], REDACTED);
+    }
 }
@@ -0,0 +1,189 @@
+/// This module tracks the state Kubernetes-style liveness and readiness probes are based on.
+///
+/// - liveness: the application's event loop is still making progress. If this fails, the application should be restarted.
+/// - readiness: every connection the application depends on (i.e. the broker) is currently up.
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Serialize;
+
+/// if the event loop hasn't checked in for this long, we consider the application dead
+pub const DEFAULT_MAX_HEARTBEAT_AGE: Duration = Duration::from_secs(60);
+
+/// component name for the connection to our own message broker
+pub const BROKER_COMPONENT: &str = "broker";
+
+/// component name for http-2-broker's connection to broker-2-http
+pub const OTHER_PROXY_COMPONENT: &str = "other_proxy";
+
+/// how often event loops should call "heartbeat()", should be well below DEFAULT_MAX_HEARTBEAT_AGE
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Shared health state. Atomics are used so that the hot message path never has to wait on a lock.
+pub struct HealthState {
+    /// each dependency readiness is based on, i.e. "broker"
+    components: Vec<(&'static str, AtomicBool)>,
+    /// unix time in milliseconds, 0 means "never"
+    last_message: AtomicU64,
+    /// unix time in milliseconds, 0 means "never"
+    last_heartbeat: AtomicU64,
+    max_heartbeat_age: Duration,
+}
+
+#[derive(Serialize)]
+pub struct ComponentStatus {
+    pub name: &'static str,
+    pub connected: bool,
+}
+
+/// JSON response for both probes
+#[derive(Serialize)]
+pub struct HealthReport {
+    /// "ok" or "unavailable"
+    pub status: &'static str,
+    pub components: Vec<ComponentStatus>,
+    /// milliseconds since the last message was successfully handled, if we've handled one
+    pub ms_since_last_message: Option<u64>,
+    /// milliseconds since the event loop last checked in, if it has checked in
+    pub ms_since_last_heartbeat: Option<u64>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn ms_since(timestamp: &AtomicU64) -> Option<u64> {
+    match timestamp.load(Ordering::Relaxed) {
+        0 => None,
+        ts => Some(now_ms().saturating_sub(ts)),
+    }
+}
+
+impl HealthState {
+    /// Create the health state. All components start out disconnected.
+    pub fn new(components: &[&'static str], max_heartbeat_age: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            components: components
+                .iter()
+                .map(|name| (*name, AtomicBool::new(false)))
+                .collect(),
+            last_message: AtomicU64::new(0),
+            // treat startup as the first heartbeat, so we're not killed before the event loop starts
+            last_heartbeat: AtomicU64::new(now_ms()),
+            max_heartbeat_age,
+        })
+    }
+
+    /// Mark a component as connected or disconnected. Unknown component names are ignored.
+    pub fn set_connected(&self, component: &str, connected: bool) {
+        if let Some((_, state)) = self.components.iter().find(|(name, _)| *name == component) {
+            state.store(connected, Ordering::Relaxed);
+        }
+    }
+
+    /// call this whenever a message is successfully handled
+    pub fn record_message(&self) {
+        self.last_message.store(now_ms(), Ordering::Relaxed);
+    }
+
+    /// call this periodically (see HEARTBEAT_INTERVAL) from the application's event loop
+    pub fn heartbeat(&self) {
+        self.last_heartbeat.store(now_ms(), Ordering::Relaxed);
+    }
+
+    pub fn is_live(&self) -> bool {
+        ms_since(&self.last_heartbeat)
+            .is_some_and(|ms| ms <= self.max_heartbeat_age.as_millis() as u64)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.components
+            .iter()
+            .all(|(_, connected)| connected.load(Ordering::Relaxed))
+    }
+
+    /// the same report "/readyz" returns
+    pub fn readiness_report(&self) -> HealthReport {
+        self.report(self.is_ready())
+    }
+
+    fn report(&self, healthy: bool) -> HealthReport {
+        HealthReport {
+            status: if healthy { "ok" } else { "unavailable" },
+            components: self
+                .components
+                .iter()
+                .map(|(name, connected)| ComponentStatus {
+                    name,
+                    connected: connected.load(Ordering::Relaxed),
+                })
+                .collect(),
+            ms_since_last_message: ms_since(&self.last_message),
+            ms_since_last_heartbeat: ms_since(&self.last_heartbeat),
+        }
+    }
+}
+
+fn report_response(healthy: bool, report: HealthReport) -> impl IntoResponse {
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Return `200 OK` if the event loop is still making progress, `503 Service Unavailable` otherwise
+pub async fn livez_handler(State(health): State<Arc<HealthState>>) -> impl IntoResponse {
+    let live = health.is_live();
+    report_response(live, health.report(live))
+}
+
+/// Return `200 OK` if all dependencies are connected, `503 Service Unavailable` otherwise
+pub async fn readyz_handler(State(health): State<Arc<HealthState>>) -> impl IntoResponse {
+    let report = health.readiness_report();
+    report_response(health.is_ready(), report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_only_when_all_components_connected() {
+        let health = HealthState::new(
+            &[BROKER_COMPONENT, OTHER_PROXY_COMPONENT],
+            DEFAULT_MAX_HEARTBEAT_AGE,
+        );
+        assert!(!health.is_ready());
+
+        health.set_connected(BROKER_COMPONENT, true);
+        assert!(!health.is_ready());
+
+        health.set_connected(OTHER_PROXY_COMPONENT, true);
+        assert!(health.is_ready());
+
+        health.set_connected(BROKER_COMPONENT, false);
+        assert!(!health.is_ready());
+    }
+
+    #[test]
+    fn not_live_when_heartbeat_is_stale() {
+        let health = HealthState::new(&[BROKER_COMPONENT], Duration::ZERO);
+        health.last_heartbeat.store(1, Ordering::Relaxed);
+        assert!(!health.is_live());
+
+        let health = HealthState::new(&[BROKER_COMPONENT], DEFAULT_MAX_HEARTBEAT_AGE);
+        health.heartbeat();
+        assert!(health.is_live());
+    }
+}
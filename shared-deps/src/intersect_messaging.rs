@@ -1,5 +1,7 @@
 /// This module contains all of the core INTERSECT logic regarding messages.
-use serde::Deserialize;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
 
 /// should use a non-printable delimiter which should not appear in a channel definition, but is also not the EOF character.
 /// Note that chars in Rust assume valid UTF-8. UTF-8 is probably the most efficient approach.
@@ -7,6 +9,44 @@ use serde::Deserialize;
 /// Currently, all SSE APIs seem to mandate using Strings, though...
 const DELIMITER: char = '\x01';
 
+/// separates the (optional) metadata section from the channel. Like the channel, metadata never contains the main delimiter.
+const METADATA_DELIMITER: char = '\x02';
+
+/// separates individual "key=value" metadata entries from each other
+const METADATA_ENTRY_DELIMITER: char = '\x1f';
+
+/// SSE event type used for a batch of messages. Single messages use the default "message" event type.
+pub const BATCH_EVENT: &str = "batch";
+
+/// request header a subscriber sends to tell broker-2-http it understands batch events.
+/// Subscribers which don't send it only ever get single messages.
+pub const ACCEPT_BATCH_HEADER: &str = "x-intersect-accept-batch";
+
+/// request header a subscriber sends to tell broker-2-http it will confirm every message with "POST /ack".
+/// broker-2-http then only acknowledges messages on its broker once the subscriber has confirmed them.
+pub const CONFIRM_HEADER: &str = "x-intersect-confirm";
+
+/// request header a subscriber sends to tell broker-2-http it understands metadata before the channel (see "make_eventsource_data_with_metadata").
+/// Subscribers which don't send it get messages without metadata, older versions would take the metadata for part of the channel.
+pub const ACCEPT_METADATA_HEADER: &str = "x-intersect-accept-metadata";
+
+/// SSE event type broker-2-http starts the stream with for subscribers which confirm messages, its data is the client id to use in "POST /ack".
+/// Every other event then has the message id(s) as its SSE id, separated by commas for batches.
+pub const CLIENT_EVENT: &str = "client";
+
+/// Body of "POST /ack" on broker-2-http
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct AckRequest {
+    /// client id from the CLIENT_EVENT
+    pub client: String,
+    /// ids of messages which were published
+    #[serde(default)]
+    pub ack: Vec<u64>,
+    /// ids of messages which could not be published, and should be requeued
+    #[serde(default)]
+    pub nack: Vec<u64>,
+}
+
 /// idea is that INTERSECT will just use one AMQP exchange for everything, things get separated based off of the routing key
 pub const INTERSECT_MESSAGE_EXCHANGE: &str = "intersect-messages";
 
@@ -37,12 +77,86 @@ pub fn should_message_passthrough(
 // A NOTE REGARDING THE HTTP EVENTSOURCE STRINGS:
 // The values are just the channel concatenated with the message string, separated by a non-printable byte (1)
 // since channels always follow a specific format, but messages can have many arbitrary characters in them, list the channel first.
+// Metadata which isn't part of the INTERSECT message (i.e. trace context) can optionally be listed before the channel,
+// separated from the channel by another non-printable byte (2). Without metadata, the string is exactly the same as before.
 
 /// build the event source data string
 pub fn make_eventsource_data(channel: &str, msg_str: &str) -> String {
     format!("{}{}{}", channel, DELIMITER, msg_str)
 }
 
+/// build the event source data string, including metadata which should travel with the message.
+///
+/// Metadata keys and values containing any of our delimiters will be skipped.
+pub fn make_eventsource_data_with_metadata(
+    channel: &str,
+    msg_str: &str,
+    metadata: &HashMap<String, String>,
+) -> String {
+    let has_delimiter =
+        |s: &str| s.contains([DELIMITER, METADATA_DELIMITER, METADATA_ENTRY_DELIMITER]);
+    let entries: Vec<String> = metadata
+        .iter()
+        .filter(|(k, v)| {
+            // values are allowed to contain '=' (i.e. W3C tracestate), keys are not
+            let valid = !has_delimiter(k) && !k.contains('=') && !has_delimiter(v);
+            if !valid {
+                tracing::warn!("metadata entry {} cannot be encoded, skipping it", k);
+            }
+            valid
+        })
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    if entries.is_empty() {
+        return make_eventsource_data(channel, msg_str);
+    }
+    format!(
+        "{}{}{}",
+        entries.join(&METADATA_ENTRY_DELIMITER.to_string()),
+        METADATA_DELIMITER,
+        make_eventsource_data(channel, msg_str)
+    )
+}
+
+/// the event source data string without its metadata, for subscribers which don't understand metadata
+pub fn strip_eventsource_metadata(data: &str) -> &str {
+    // metadata only comes before the channel, a metadata delimiter in the message itself doesn't count
+    match (data.find(METADATA_DELIMITER), data.find(DELIMITER)) {
+        (Some(metadata_end), Some(channel_start)) if metadata_end < channel_start => {
+            &data[metadata_end + METADATA_DELIMITER.len_utf8()..]
+        }
+        _ => data,
+    }
+}
+
+/// build the data of a batch event: a JSON array of event source data strings (as built by "make_eventsource_data")
+pub fn make_eventsource_batch<S: AsRef<str>>(events: &[S]) -> String {
+    let events: Vec<&str> = events.iter().map(AsRef::as_ref).collect();
+    // serializing a list of strings can't fail
+    serde_json::to_string(&events).unwrap()
+}
+
+/// returns the individual event source data strings of a batch event, which can then be passed to "extract_eventsource_data"
+pub fn extract_eventsource_batch(data: &str) -> Result<Vec<String>, ExtractEventSourceErr> {
+    serde_json::from_str(data).map_err(|e| {
+        tracing::warn!(error = ?e, "Batch data from SSE is not a JSON array of strings");
+        ExtractEventSourceErr
+    })
+}
+
+/// build the SSE id of an event carrying these messages
+pub fn make_event_ids(ids: &[u64]) -> String {
+    ids.iter().map(u64::to_string).collect::<Vec<_>>().join(",")
+}
+
+/// returns the message ids of an event, or None if it doesn't have (valid) ids
+pub fn extract_event_ids(event_id: &str) -> Option<Vec<u64>> {
+    if event_id.is_empty() {
+        return None;
+    }
+    event_id.split(',').map(|id| id.parse().ok()).collect()
+}
+
 #[derive(Debug)]
 pub struct ExtractEventSourceErr;
 
@@ -56,8 +170,28 @@ impl std::error::Error for ExtractEventSourceErr {}
 
 /// returns a tuple of the channel string and the event source string
 pub fn extract_eventsource_data(data: &str) -> Result<(String, String), ExtractEventSourceErr> {
+    extract_eventsource_data_with_metadata(data).map(|(channel, msg_str, _)| (channel, msg_str))
+}
+
+/// returns a tuple of the channel string, the event source string, and any metadata sent along with them
+pub fn extract_eventsource_data_with_metadata(
+    data: &str,
+) -> Result<(String, String, HashMap<String, String>), ExtractEventSourceErr> {
     match data.split_once(DELIMITER) {
-        Some((channel, msg_str)) => Ok((channel.to_owned(), msg_str.to_owned())),
+        Some((head, msg_str)) => {
+            let (metadata, channel) = match head.rsplit_once(METADATA_DELIMITER) {
+                Some((metadata_str, channel)) => (
+                    metadata_str
+                        .split(METADATA_ENTRY_DELIMITER)
+                        .filter_map(|entry| entry.split_once('='))
+                        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                        .collect(),
+                    channel,
+                ),
+                None => (HashMap::new(), head),
+            };
+            Ok((channel.to_owned(), msg_str.to_owned(), metadata))
+        }
         None => {
             tracing::warn!("Data from SSE does not match expected format: {}", data);
             Err(ExtractEventSourceErr)
@@ -70,6 +204,8 @@ mod tests {
     use super::*;
 
     #[test]
+    // kept as originally written
+    #[allow(clippy::bool_comparison)]
     fn should_return_true_if_this_system_in_message() {
         let sample_message = r#"{"messageId":"39d9c119-3b0a-474e-ae3d-f3eb5f8d3a86","operationId":"say_hello_to_name","contentType":"application/json","payload":"\"hello_client\"","headers":{"destination":"tmp-4b19600a-527d-4a0b-9bf7-f500d9656350.tmp-.tmp-.-.tmp-","source":"hello-organization.hello-facility.hello-system.hello-subsystem.hello-service","sdk_version":"0.6.2","created_at":"2024-06-28T15:14:39.117515Z","data_handler":0,"has_error":false}}"#;
 
@@ -81,6 +217,8 @@ mod tests {
     }
 
     #[test]
+    // kept as originally written
+    #[allow(clippy::bool_comparison)]
     fn should_return_false_if_this_system_not_in_message() {
         let sample_message = r#"{"messageId":"39d9c119-3b0a-474e-ae3d-f3eb5f8d3a86","operationId":"say_hello_to_name","contentType":"application/json","payload":"\"hello_client\"","headers":{"destination":"tmp-4b19600a-527d-4a0b-9bf7-f500d9656350.tmp-.tmp-.-.tmp-","source":"hello-organization.hello-facility.hello-system.hello-subsystem.hello-service","sdk_version":"0.6.2","created_at":"2024-06-28T15:14:39.117515Z","data_handler":0,"has_error":false}}"#;
 
@@ -114,4 +252,74 @@ mod tests {
         assert_eq!(channel, decoded_channel);
         assert_eq!(message, decoded_message);
     }
+
+    #[test]
+    fn encode_decode_eventsource_msg_with_metadata() {
+        let channel = "channel";
+        let message = "mess\x01age\x02"; // message can use both delimiters
+        let metadata = HashMap::from([
+            (
+                "traceparent".to_owned(),
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_owned(),
+            ),
+            ("tracestate".to_owned(), "congo=t61rcWkgMzE".to_owned()),
+        ]);
+
+        let encoded = make_eventsource_data_with_metadata(channel, message, &metadata);
+
+        let (decoded_channel, decoded_message, decoded_metadata) =
+            extract_eventsource_data_with_metadata(&encoded).unwrap();
+        assert_eq!(channel, decoded_channel);
+        assert_eq!(message, decoded_message);
+        assert_eq!(metadata, decoded_metadata);
+
+        // consumers which don't care about metadata still get the right channel
+        let (decoded_channel, _) = extract_eventsource_data(&encoded).unwrap();
+        assert_eq!(channel, decoded_channel);
+    }
+
+    #[test]
+    fn empty_metadata_uses_plain_format() {
+        let encoded = make_eventsource_data_with_metadata("channel", "message", &HashMap::new());
+        assert_eq!(encoded, make_eventsource_data("channel", "message"));
+    }
+
+    #[test]
+    fn encode_decode_eventsource_batch() {
+        let events = vec![
+            make_eventsource_data("channel.one", "{\"a\": 1}"),
+            make_eventsource_data("channel.two", "mess\x01age\x02"),
+        ];
+
+        let batch = make_eventsource_batch(&events);
+        assert_eq!(extract_eventsource_batch(&batch).unwrap(), events);
+        assert!(extract_eventsource_batch("not a batch").is_err());
+    }
+
+    #[test]
+    fn encode_decode_event_ids() {
+        assert_eq!(extract_event_ids(&make_event_ids(&[7])), Some(vec![7]));
+        assert_eq!(
+            extract_event_ids(&make_event_ids(&[1, 2, 3])),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(extract_event_ids(""), None);
+        assert_eq!(extract_event_ids("1,two"), None);
+    }
+
+    #[test]
+    fn metadata_can_be_stripped_for_older_subscribers() {
+        let metadata = HashMap::from([("traceparent".to_owned(), "00-abc-def-01".to_owned())]);
+        let data = make_eventsource_data_with_metadata(
+            "org.facility.system.userspace",
+            "message with \x02 in it",
+            &metadata,
+        );
+        let stripped = strip_eventsource_metadata(&data);
+        assert_eq!(
+            stripped,
+            make_eventsource_data("org.facility.system.userspace", "message with \x02 in it")
+        );
+        assert_eq!(strip_eventsource_metadata(stripped), stripped);
+    }
 }
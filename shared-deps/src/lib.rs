@@ -1,5 +1,10 @@
+pub mod cli;
 pub mod configuration;
+pub mod health;
 pub mod intersect_messaging;
+pub mod metrics;
 pub mod protocols;
+pub mod recording;
+pub mod reload;
 pub mod signals;
 pub mod telemetry;
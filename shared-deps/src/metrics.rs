@@ -0,0 +1,81 @@
+/// This module contains the Prometheus logic shared across both applications.
+/// Application-specific metrics are defined in each application, but everything is registered on the default Prometheus registry,
+/// so a single call to "metrics_handler" exposes all of them.
+use std::sync::LazyLock;
+
+use axum::http::{header::CONTENT_TYPE, StatusCode};
+use axum::response::IntoResponse;
+use prometheus::{register_int_counter, Encoder, IntCounter, TextEncoder};
+
+/// number of times we had to re-establish a broker connection after initially connecting
+pub static AMQP_RECONNECTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "amqp_reconnects_total",
+        "Number of times a broker connection was re-established after being lost"
+    )
+    .unwrap()
+});
+
+/// number of failed attempts to connect to the broker
+pub static AMQP_CONNECTION_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "amqp_connection_failures_total",
+        "Number of failed attempts to open a broker connection"
+    )
+    .unwrap()
+});
+
+/// number of times a changed configuration was applied while running
+pub static CONFIG_RELOADS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "config_reloads_total",
+        "Number of times a changed configuration was applied without restarting"
+    )
+    .unwrap()
+});
+
+/// number of times a changed configuration was not applied, because it was invalid or needs a restart
+pub static CONFIG_RELOAD_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "config_reload_failures_total",
+        "Number of times a changed configuration could not be applied without restarting"
+    )
+    .unwrap()
+});
+
+/// Encode all registered metrics into the Prometheus text exposition format.
+pub fn gather_metrics() -> Result<String, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    // the text encoder only ever writes valid UTF-8
+    Ok(String::from_utf8(buffer).unwrap_or_default())
+}
+
+/// Return all metrics in the Prometheus text format, can be mounted on any axum Router
+pub async fn metrics_handler() -> impl IntoResponse {
+    match gather_metrics() {
+        Ok(body) => (
+            StatusCode::OK,
+            [(CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+            body,
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::error!(error = ?e, "could not encode metrics");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gathered_metrics_include_registered_counters() {
+        AMQP_RECONNECTS.inc();
+
+        let output = gather_metrics().unwrap();
+        assert!(output.contains("amqp_reconnects_total"));
+    }
+}
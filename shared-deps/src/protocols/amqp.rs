@@ -2,11 +2,18 @@ use amqprs::{
     callbacks::{DefaultChannelCallback, DefaultConnectionCallback},
     channel::{Channel, ExchangeDeclareArguments},
     connection::{Connection, OpenConnectionArguments},
+    FieldTable, FieldValue,
 };
 use secrecy::ExposeSecret;
-use std::time::Duration;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
 
-use crate::{configuration::BrokerSettings, intersect_messaging::INTERSECT_MESSAGE_EXCHANGE};
+use crate::{
+    configuration::BrokerSettings,
+    intersect_messaging::INTERSECT_MESSAGE_EXCHANGE,
+    metrics::{AMQP_CONNECTION_FAILURES, AMQP_RECONNECTS},
+};
 
 /// Connect to the broker, attempt to reconnect if failed initially.
 /// if retries = 0, retry forever
@@ -27,6 +34,7 @@ pub async fn get_connection(connection_details: &BrokerSettings, retries: u32) -
 
     let mut attempts = 0;
     while res.is_err() {
+        AMQP_CONNECTION_FAILURES.inc();
         if retries != 0 {
             attempts += 1;
             if attempts > retries {
@@ -66,6 +74,48 @@ pub async fn get_channel(connection: &Connection) -> Channel {
     channel
 }
 
+/// One broker connection for everything in the process which talks to the same broker, each user opens its own channels on it.
+/// Whoever first finds the connection closed reconnects it, everyone else then gets the new connection.
+pub struct SharedConnection {
+    settings: BrokerSettings,
+    /// None until the first "get()"
+    connection: Mutex<Option<Connection>>,
+}
+
+impl SharedConnection {
+    pub fn new(settings: BrokerSettings) -> Arc<Self> {
+        Arc::new(Self {
+            settings,
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// Returns an open connection, connecting first if needed ("retries" works like in "get_connection").
+    pub async fn get(&self, retries: u32) -> Connection {
+        let mut connection = self.connection.lock().await;
+        match connection.as_ref() {
+            Some(open) if open.is_open() => open.clone(),
+            previous => {
+                if previous.is_some() {
+                    AMQP_RECONNECTS.inc();
+                }
+                let new = get_connection(&self.settings, retries).await;
+                *connection = Some(new.clone());
+                new
+            }
+        }
+    }
+
+    /// close the connection, call this once nothing uses it anymore
+    pub async fn close(&self) {
+        if let Some(connection) = self.connection.lock().await.take() {
+            if let Err(e) = connection.close().await {
+                tracing::warn!(error = ?e, "could not close connection");
+            }
+        }
+    }
+}
+
 /// logic for declaring the INTERSECT exchange - need to do this in case no services/systems have declared it
 ///
 /// Returns:
@@ -88,7 +138,96 @@ pub fn is_name_compliant(name: &str) -> bool {
             .any(|c| !c.is_alphanumeric() && c != '-' && c != '_' && c != '.' && c != ':')
 }
 
+/// Turn any string into a valid AMQP queue name (see "is_name_compliant").
+/// Compliant names are returned as-is. Otherwise, invalid characters are replaced,
+/// the name is truncated, and a SHA-256 hash of the original name is appended so that distinct names stay distinct.
+pub fn compliant_queue_name(name: &str) -> String {
+    if is_name_compliant(name) {
+        return name.to_owned();
+    }
+    let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+    // 127 max length - 64 characters of hash - 1 separator
+    let readable: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(62)
+        .collect();
+    format!("{}-{}", readable, hash)
+}
+
 /// make sure that the routing key is valid for AMQP
 pub fn is_routing_key_compliant(key: &str) -> bool {
     key.len() < 256
 }
+
+/// collect all string-valued AMQP headers (i.e. W3C trace context), other header types are ignored
+pub fn string_headers(headers: Option<&FieldTable>) -> HashMap<String, String> {
+    let Some(headers) = headers else {
+        return HashMap::new();
+    };
+    headers
+        .as_ref()
+        .iter()
+        .filter_map(|(k, v)| match v {
+            FieldValue::S(value) => Some((k.to_string(), value.to_string())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// convert string headers into AMQP headers, entries too long for AMQP are skipped
+pub fn to_field_table(headers: &HashMap<String, String>) -> FieldTable {
+    let mut table = FieldTable::new();
+    for (k, v) in headers {
+        match (k.as_str().try_into(), v.as_str().try_into()) {
+            (Ok(key), Ok(value)) => {
+                table.insert(key, FieldValue::S(value));
+            }
+            _ => tracing::warn!("header {} is too long for AMQP, skipping it", k),
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_headers_roundtrip() {
+        let headers = HashMap::from([(
+            "traceparent".to_owned(),
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_owned(),
+        )]);
+
+        let mut table = to_field_table(&headers);
+        table.insert("x-count".try_into().unwrap(), FieldValue::l(1));
+
+        assert_eq!(string_headers(Some(&table)), headers);
+        assert!(string_headers(None).is_empty());
+    }
+
+    #[test]
+    fn queue_names_are_made_compliant() {
+        assert_eq!(
+            compliant_queue_name("broker-2-http.organization.facility.system"),
+            "broker-2-http.organization.facility.system"
+        );
+
+        let long_name = format!("broker-2-http.{}", "a".repeat(200));
+        let hashed = compliant_queue_name(&long_name);
+        assert!(is_name_compliant(&hashed));
+        assert!(hashed.starts_with("broker-2-http.aaa"));
+        assert_ne!(hashed, compliant_queue_name(&format!("{}b", long_name)));
+
+        let hashed = compliant_queue_name("broker 2 http/ünicode");
+        assert!(is_name_compliant(&hashed));
+        assert!(hashed.starts_with("broker_2_http__nicode-"));
+    }
+}
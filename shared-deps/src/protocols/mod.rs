@@ -1 +1,3 @@
 pub mod amqp;
+pub mod publish_confirm;
+pub mod websocket;
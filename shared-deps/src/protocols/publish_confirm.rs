@@ -0,0 +1,124 @@
+/// Publisher confirms: the broker tells us once it has taken responsibility for a message we published.
+///
+/// Each message is published on its own channel, so the first ack or nack on the channel is for that message.
+/// Messages published as "mandatory" which the broker could not route are returned before they are acked, and count as rejected.
+use std::time::Duration;
+
+use amqprs::{
+    callbacks::ChannelCallback,
+    channel::{BasicPublishArguments, Channel, ConfirmSelectArguments},
+    connection::Connection,
+    Ack, BasicProperties, Cancel, CloseChannel, Nack, Return,
+};
+use async_trait::async_trait;
+use tokio::sync::oneshot;
+
+/// Reports the broker's answer to the single message published on its channel
+struct PublishConfirmCallback {
+    /// None once the answer was reported
+    result: Option<oneshot::Sender<bool>>,
+}
+
+impl PublishConfirmCallback {
+    fn report(&mut self, confirmed: bool) {
+        if let Some(result) = self.result.take() {
+            let _ = result.send(confirmed);
+        }
+    }
+}
+
+#[async_trait]
+impl ChannelCallback for PublishConfirmCallback {
+    async fn close(
+        &mut self,
+        channel: &Channel,
+        close: CloseChannel,
+    ) -> Result<(), amqprs::error::Error> {
+        tracing::error!("broker closed channel {}: {}", channel, close);
+        self.report(false);
+        Ok(())
+    }
+
+    async fn cancel(
+        &mut self,
+        _channel: &Channel,
+        _cancel: Cancel,
+    ) -> Result<(), amqprs::error::Error> {
+        Ok(())
+    }
+
+    async fn flow(
+        &mut self,
+        _channel: &Channel,
+        _active: bool,
+    ) -> Result<bool, amqprs::error::Error> {
+        Ok(true)
+    }
+
+    async fn publish_ack(&mut self, _channel: &Channel, _ack: Ack) {
+        self.report(true);
+    }
+
+    async fn publish_nack(&mut self, _channel: &Channel, nack: Nack) {
+        tracing::warn!("broker rejected delivery tag {}", nack.delivery_tag());
+        self.report(false);
+    }
+
+    async fn publish_return(
+        &mut self,
+        _channel: &Channel,
+        ret: Return,
+        _basic_properties: BasicProperties,
+        _content: Vec<u8>,
+    ) {
+        tracing::warn!("broker returned message: {}", ret);
+        self.report(false);
+    }
+}
+
+/// Open a channel in confirm mode. The receiver resolves to true once the broker has confirmed the first message published on it.
+pub async fn open_confirmed_channel(
+    connection: &Connection,
+) -> Result<(Channel, oneshot::Receiver<bool>), amqprs::error::Error> {
+    let channel = connection.open_channel(None).await?;
+    let (result, confirmed) = oneshot::channel();
+    channel
+        .register_callback(PublishConfirmCallback {
+            result: Some(result),
+        })
+        .await?;
+    channel
+        .confirm_select(ConfirmSelectArguments::default())
+        .await?;
+    Ok((channel, confirmed))
+}
+
+/// Publish a single message as "mandatory" on its own channel in confirm mode, and wait at most "timeout" for the broker.
+/// Returns whether the broker confirmed (and could route) the message.
+pub async fn publish_confirmed(
+    connection: &Connection,
+    properties: BasicProperties,
+    content: Vec<u8>,
+    exchange: &str,
+    routing_key: &str,
+    timeout: Duration,
+) -> Result<bool, amqprs::error::Error> {
+    let (channel, confirmed) = open_confirmed_channel(connection).await?;
+    let published = channel
+        .basic_publish(
+            properties,
+            content,
+            // unroutable messages are returned to us, rather than silently dropped
+            BasicPublishArguments::new(exchange, routing_key)
+                .mandatory(true)
+                .finish(),
+        )
+        .await;
+    let confirmed = match published {
+        Ok(_) => matches!(tokio::time::timeout(timeout, confirmed).await, Ok(Ok(true))),
+        Err(_) => false,
+    };
+    // we NEED to explicitly close the channel, or else problems on the broker may develop
+    channel.close().await?;
+    published.map(|_| confirmed)
+}
@@ -0,0 +1,68 @@
+/// Frames broker-2-http and http-2-broker exchange over the WebSocket transport.
+///
+/// broker-2-http sends each message as a binary frame: the message id (8 bytes, big-endian), followed by the event source data (as built by "make_eventsource_data").
+/// The receiver answers every message with a text frame containing a Receipt, i.e. {"ack": 42} once it has published message 42, or {"nack": 42} if it could not.
+use serde::{Deserialize, Serialize};
+
+use crate::intersect_messaging::ExtractEventSourceErr;
+
+const ID_LENGTH: usize = std::mem::size_of::<u64>();
+
+/// The receiver's answer to a single message
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Receipt {
+    /// the message was published, the sender may acknowledge it on its broker
+    Ack(u64),
+    /// the message was not published, the sender should requeue it
+    Nack(u64),
+}
+
+/// build the binary frame for a single message
+pub fn encode_message(id: u64, data: &str) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ID_LENGTH + data.len());
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(data.as_bytes());
+    frame
+}
+
+/// returns the message id and event source data of a binary frame
+pub fn decode_message(frame: &[u8]) -> Result<(u64, &str), ExtractEventSourceErr> {
+    if frame.len() < ID_LENGTH {
+        tracing::warn!("WebSocket frame is too short to contain a message id");
+        return Err(ExtractEventSourceErr);
+    }
+    let (id, data) = frame.split_at(ID_LENGTH);
+    let id = u64::from_be_bytes(id.try_into().unwrap());
+    match std::str::from_utf8(data) {
+        Ok(data) => Ok((id, data)),
+        Err(e) => {
+            tracing::warn!(error = ?e, "WebSocket message {} is not UTF-8", id);
+            Err(ExtractEventSourceErr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_message() {
+        let frame = encode_message(42, "channel\x01message");
+        assert_eq!(decode_message(&frame).unwrap(), (42, "channel\x01message"));
+        assert!(decode_message(&frame[..4]).is_err());
+    }
+
+    #[test]
+    fn receipt_format() {
+        assert_eq!(
+            serde_json::to_string(&Receipt::Ack(42)).unwrap(),
+            r#"{"ack":42}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Receipt>(r#"{"nack": 7}"#).unwrap(),
+            Receipt::Nack(7)
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! The format of recordings broker-2-http makes of what it forwards, which http-2-broker's replay tool reads.
+//! A recording has one JSON object per line, see "RecordedEvent".
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::value::RawValue;
+
+/// One message we forwarded to the other proxy
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct RecordedEvent {
+    /// when we forwarded it, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// the routing key it was published with on our broker
+    pub routing_key: String,
+    /// the INTERSECT message itself, kept as JSON so recordings are easy to read
+    pub envelope: Box<RawValue>,
+}
+
+impl RecordedEvent {
+    /// Record "envelope" as forwarded now, compacted so the event fits on one line. Fails if the envelope is not JSON.
+    pub fn new(routing_key: &str, envelope: &str) -> Result<Self, serde_json::Error> {
+        Ok(Self {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or_default(),
+            routing_key: routing_key.to_owned(),
+            envelope: serde_json::value::to_raw_value(&serde_json::from_str::<serde_json::Value>(
+                envelope,
+            )?)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_recorded_as_single_lines() {
+        let event = RecordedEvent::new(
+            "organization.facility.system.userspace",
+            r#"{"headers": {"source": "organization.facility.system"}, "payload": "hi"}"#,
+        )
+        .unwrap();
+        let line = serde_json::to_string(&event).unwrap();
+        assert!(!line.contains('\n'));
+
+        let read: RecordedEvent = serde_json::from_str(&line).unwrap();
+        assert_eq!(read.routing_key, event.routing_key);
+        assert_eq!(read.envelope.get(), event.envelope.get());
+        assert!(RecordedEvent::new("topic", "not json").is_err());
+
+        let pretty = RecordedEvent::new(
+            "organization.facility.system.userspace",
+            "{\n  \"headers\": {\n    \"source\": \"organization.facility.system\"\n  },\n  \"payload\": \"hi\"\n}\n",
+        )
+        .unwrap();
+        assert!(!serde_json::to_string(&pretty).unwrap().contains('\n'));
+    }
+}
@@ -0,0 +1,246 @@
+//! Applying configuration changes without restarting.
+//!
+//! The configuration is re-read whenever we get SIGHUP or SIGUSR1, or when the configuration file changes.
+//! A new configuration is only applied if it is valid, and if every setting which changed can be reloaded;
+//! otherwise we keep running with the current configuration as a whole, and log why.
+use std::path::Path;
+use std::time::Duration;
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use secrecy::{ExposeSecret, Secret};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+use crate::cli::Cli;
+use crate::configuration::{get_valid_configuration, setting_path, Validate};
+use crate::metrics::{CONFIG_RELOADS, CONFIG_RELOAD_FAILURES};
+use crate::signals::ReloadSignals;
+
+/// editors and Kubernetes tend to replace a file in several steps, wait for them to finish before reading it
+const FILE_CHANGE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Settings which can be re-read while running
+pub trait Reload: Validate + Serialize {
+    /// paths of the settings (or whole sections of settings) which can change without a restart
+    const RELOADABLE: &'static [&'static str];
+
+    /// Secrets are redacted when settings are compared, so add the paths of the secrets which differ in "new" to "changed".
+    /// "path" is where these settings are, as in "Validate".
+    fn changed_secrets(&self, new: &Self, path: &str, changed: &mut Vec<String>);
+}
+
+/// Record the secret at "path" as changed if "new" differs from "current", see "Reload::changed_secrets"
+pub fn compare_secret(
+    path: &str,
+    current: &Secret<String>,
+    new: &Secret<String>,
+    changed: &mut Vec<String>,
+) {
+    if current.expose_secret() != new.expose_secret() {
+        changed.push(path.to_owned());
+    }
+}
+
+/// Paths of every setting which differs between "current" and "new", as they would be printed.
+/// A section which was added or removed is a single change.
+pub fn changed_settings<T: Serialize>(current: &T, new: &T) -> Vec<String> {
+    let mut changed = Vec::new();
+    // serializing our own settings can't fail
+    diff(
+        "",
+        &serde_json::to_value(current).unwrap(),
+        &serde_json::to_value(new).unwrap(),
+        &mut changed,
+    );
+    changed
+}
+
+fn diff(path: &str, current: &Value, new: &Value, changed: &mut Vec<String>) {
+    match (current, new) {
+        (Value::Object(current), Value::Object(new)) => {
+            for (name, value) in current {
+                diff(
+                    &setting_path(path, name),
+                    value,
+                    new.get(name).unwrap_or(&Value::Null),
+                    changed,
+                );
+            }
+            for (name, value) in new {
+                if !current.contains_key(name) {
+                    diff(&setting_path(path, name), &Value::Null, value, changed);
+                }
+            }
+        }
+        (current, new) if current != new => changed.push(path.to_owned()),
+        _ => {}
+    }
+}
+
+/// Whether the setting at "path" is one of the "reloadable" settings, or inside one of them
+pub fn is_reloadable(reloadable: &[&str], path: &str) -> bool {
+    reloadable.iter().any(|section| {
+        path == *section
+            || path
+                .strip_prefix(section)
+                .is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
+/// Notifies us of changes to the configuration file.
+/// We watch its directory rather than the file itself, since the file is often replaced instead of modified
+/// (i.e. Kubernetes swaps a symlink when a ConfigMap changes).
+struct ConfigFileWatcher {
+    // changes stop being reported once this is dropped
+    _watcher: RecommendedWatcher,
+    changes: mpsc::UnboundedReceiver<()>,
+}
+
+impl ConfigFileWatcher {
+    /// Watch the configuration file, if there is one
+    fn new(file: Option<&Path>) -> Option<Self> {
+        let path = file?;
+        let file = path.display();
+        let directory = match path.parent() {
+            Some(directory) if !directory.as_os_str().is_empty() => directory.to_owned(),
+            _ => Path::new(".").to_owned(),
+        };
+        let name = path.file_name()?.to_owned();
+        let (sender, changes) = mpsc::unbounded_channel();
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let Ok(event) = event else { return };
+            // other files in the directory are none of our business, except Kubernetes' "..data" symlink
+            let ours = event.paths.iter().any(|path| {
+                path.file_name().is_some_and(|changed| {
+                    changed == name || changed.to_string_lossy().starts_with("..")
+                })
+            });
+            if ours && !event.kind.is_access() {
+                let _ = sender.send(());
+            }
+        })
+        .and_then(|mut watcher| {
+            watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        });
+        match watcher {
+            Ok(watcher) => Some(Self {
+                _watcher: watcher,
+                changes,
+            }),
+            Err(e) => {
+                tracing::error!(error = ?e, "could not watch {} for changes, only reloading configuration on SIGHUP", file);
+                None
+            }
+        }
+    }
+
+    /// Resolves once the file has changed and has been left alone for a moment
+    async fn changed(&mut self) {
+        if self.changes.recv().await.is_none() {
+            return std::future::pending().await;
+        }
+        loop {
+            tokio::time::sleep(FILE_CHANGE_DEBOUNCE).await;
+            let mut more_changes = false;
+            while self.changes.try_recv().is_ok() {
+                more_changes = true;
+            }
+            if !more_changes {
+                return;
+            }
+        }
+    }
+}
+
+async fn file_changed(watcher: &mut Option<ConfigFileWatcher>) {
+    match watcher {
+        Some(watcher) => watcher.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Re-read the configuration with "read" (usually "Cli::read_configuration") whenever it may have changed. Runs forever.
+///
+/// "apply" gets the new settings along with the paths of the settings which changed, and has to apply all of them,
+/// so that everything reloaded at once takes effect together.
+pub async fn reload_on_change<T: Reload>(
+    cli: Cli,
+    mut current: T,
+    read: impl Fn(&Cli) -> Result<T, config::ConfigError>,
+    mut apply: impl FnMut(&T, &[String]),
+) {
+    let mut watcher = ConfigFileWatcher::new(cli.config.as_deref());
+    let mut signals = ReloadSignals::register();
+    loop {
+        tokio::select! {
+            _ = signals.recv() => {},
+            _ = file_changed(&mut watcher) => {},
+        }
+        let new = match get_valid_configuration(|| read(&cli)) {
+            Ok(new) => new,
+            Err(e) => {
+                CONFIG_RELOAD_FAILURES.inc();
+                tracing::error!(
+                    "not reloading configuration, keeping the current one --- {}",
+                    e
+                );
+                continue;
+            }
+        };
+        let mut changed = changed_settings(&current, &new);
+        current.changed_secrets(&new, "", &mut changed);
+        changed.sort();
+        changed.dedup();
+        if changed.is_empty() {
+            tracing::debug!("configuration is unchanged");
+            continue;
+        }
+        let restart_required: Vec<&str> = changed
+            .iter()
+            .map(String::as_str)
+            .filter(|path| !is_reloadable(T::RELOADABLE, path))
+            .collect();
+        if !restart_required.is_empty() {
+            CONFIG_RELOAD_FAILURES.inc();
+            tracing::error!(
+                "not reloading configuration, keeping the current one --- restart to change {} (only {} can be reloaded)",
+                restart_required.join(", "),
+                T::RELOADABLE.join(", ")
+            );
+            continue;
+        }
+        apply(&new, &changed);
+        CONFIG_RELOADS.inc();
+        tracing::info!("reloaded configuration, changed {}", changed.join(", "));
+        current = new;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_are_reported_by_path_and_checked_against_reloadable_sections() {
+        let current = serde_json::json!({
+            "app_port": 8080,
+            "other_proxy": { "url": "http://a/subscribe", "username": "user" },
+            "admin": null,
+        });
+        let new = serde_json::json!({
+            "app_port": 8080,
+            "other_proxy": { "url": "http://b/subscribe", "username": "user" },
+            "admin": { "username": "admin" },
+        });
+        let changed = changed_settings(&current, &new);
+        assert_eq!(changed, ["admin", "other_proxy.url"]);
+
+        let reloadable = ["other_proxy", "admin.username"];
+        assert!(is_reloadable(&reloadable, "other_proxy.url"));
+        assert!(!is_reloadable(&reloadable, "other_proxy_url"));
+        // enabling a whole section isn't the same as changing a setting inside it
+        assert!(!is_reloadable(&reloadable, "admin"));
+    }
+}
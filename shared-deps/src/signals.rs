@@ -1,4 +1,11 @@
-/// full credit to this module goes to https://github.com/Finomnis/tokio-graceful-shutdown/blob/43684d80cc5afbe49c87fbc3f8404dce0fc01144/src/signal_handling.rs
+//! full credit to the signal handling in this module goes to https://github.com/Finomnis/tokio-graceful-shutdown/blob/43684d80cc5afbe49c87fbc3f8404dce0fc01144/src/signal_handling.rs
+
+use std::{future::Future, time::Duration};
+use tokio::task::JoinHandle;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// once the shutdown timeout has passed, how long tasks get to clean up (i.e. close their channels) before we give up on them
+const CLEANUP_GRACE: Duration = Duration::from_secs(5);
 
 /// Waits for a signal that requests a graceful shutdown, like SIGTERM or SIGINT.
 #[cfg(unix)]
@@ -41,3 +48,180 @@ async fn wait_for_os_signal_impl() {
 pub async fn wait_for_os_signal() {
     wait_for_os_signal_impl().await
 }
+
+/// Signals that request the configuration be re-read, SIGHUP or SIGUSR1.
+/// The handlers are registered once, so signals arriving while nobody waits in "recv" aren't lost.
+pub struct ReloadSignals {
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    user1: tokio::signal::unix::Signal,
+}
+
+impl ReloadSignals {
+    #[cfg(unix)]
+    pub fn register() -> Self {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Self {
+            hangup: signal(SignalKind::hangup()).unwrap(),
+            user1: signal(SignalKind::user_defined1()).unwrap(),
+        }
+    }
+
+    /// Windows has no equivalent of SIGHUP, so there is nothing to register.
+    #[cfg(windows)]
+    pub fn register() -> Self {
+        Self {}
+    }
+
+    /// Waits for the next reload signal, or returns right away if one arrived since the last call.
+    #[cfg(unix)]
+    pub async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.hangup.recv() => tracing::debug!("Received SIGHUP."),
+            _ = self.user1.recv() => tracing::debug!("Received SIGUSR1."),
+        };
+    }
+
+    /// Never resolves.
+    #[cfg(windows)]
+    pub async fn recv(&mut self) {
+        std::future::pending::<()>().await
+    }
+}
+
+/// Coordinates a graceful shutdown of everything running in the process.
+///
+/// 1) once "requested()" resolves (i.e. on SIGTERM), stop taking on new work and finish what is in flight
+/// 2) if "deadline_passed()" resolves, the shutdown timeout has passed: give up on in-flight work, clean up and return
+///
+/// Long-running tasks are started with "spawn", so "wait" knows which ones to wait for.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: CancellationToken,
+    deadline: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start shutting down once we get a signal from the OS (see "wait_for_os_signal")
+    pub fn request_on_os_signal(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = wait_for_os_signal() => {
+                    tracing::warn!("Received terminate signal from OS, shutting down gracefully...");
+                    shutdown.request();
+                },
+                _ = shutdown.requested() => {},
+            }
+        });
+    }
+
+    /// Start shutting down, i.e. because something the application can't run without stopped
+    pub fn request(&self) {
+        self.requested.cancel();
+    }
+
+    /// Resolves once shutdown was requested
+    pub async fn requested(&self) {
+        self.requested.cancelled().await
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.is_cancelled()
+    }
+
+    /// Resolves once in-flight work should be abandoned
+    pub async fn deadline_passed(&self) {
+        self.deadline.cancelled().await
+    }
+
+    /// Spawn a task "wait" waits for. It should return by itself once shutdown is requested.
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tasks.spawn(task)
+    }
+
+    /// Wait for shutdown to be requested, then for every spawned task to return.
+    /// Tasks still running after "timeout" are told the deadline passed, and get a few more seconds to clean up.
+    ///
+    /// Returns the number of tasks which didn't return at all, whatever they were doing is abandoned.
+    pub async fn wait(&self, timeout: Duration) -> usize {
+        self.requested().await;
+        self.tasks.close();
+        if tokio::time::timeout(timeout, self.tasks.wait())
+            .await
+            .is_ok()
+        {
+            return 0;
+        }
+        tracing::warn!(
+            "{} tasks did not finish within {:?}, abandoning their in-flight work",
+            self.tasks.len(),
+            timeout
+        );
+        self.deadline.cancel();
+        let _ = tokio::time::timeout(CLEANUP_GRACE, self.tasks.wait()).await;
+        self.tasks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reload_signals_sent_between_waits_are_kept() {
+        let mut signals = ReloadSignals::register();
+        let pid = std::process::id().to_string();
+        // nobody is waiting for it yet
+        let killed = std::process::Command::new("kill")
+            .args(["-HUP", &pid])
+            .status()
+            .unwrap();
+        assert!(killed.success());
+        tokio::time::timeout(Duration::from_secs(5), signals.recv())
+            .await
+            .expect("the signal sent before waiting was lost");
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_tasks_then_passes_the_deadline() {
+        let shutdown = Shutdown::new();
+        let drained = Arc::new(AtomicBool::new(false));
+        let abandoned = Arc::new(AtomicBool::new(false));
+
+        // finishes its work as soon as shutdown is requested
+        let (task_shutdown, task_drained) = (shutdown.clone(), drained.clone());
+        shutdown.spawn(async move {
+            task_shutdown.requested().await;
+            task_drained.store(true, Ordering::Relaxed);
+        });
+        // never finishes its work, so it only returns once the deadline has passed
+        let (task_shutdown, task_abandoned) = (shutdown.clone(), abandoned.clone());
+        shutdown.spawn(async move {
+            task_shutdown.deadline_passed().await;
+            task_abandoned.store(true, Ordering::Relaxed);
+        });
+
+        assert!(!shutdown.is_requested());
+        shutdown.request();
+        assert_eq!(shutdown.wait(Duration::from_millis(50)).await, 0);
+        assert!(drained.load(Ordering::Relaxed));
+        assert!(abandoned.load(Ordering::Relaxed));
+    }
+}
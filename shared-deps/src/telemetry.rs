@@ -1,18 +1,106 @@
+use std::collections::HashMap;
+
+use opentelemetry::{global, trace::TracerProvider};
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::{SdkTracerProvider, Tracer},
+    Resource,
+};
 use tokio::task::JoinHandle;
-use tracing::{subscriber::set_global_default, Subscriber};
+use tracing::{subscriber::set_global_default, Span, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
-use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::{
+    fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, reload, EnvFilter, Registry,
+};
+
+use crate::configuration::LogLevel;
+
+/// Exports our spans to an OpenTelemetry collector over OTLP (HTTP + protobuf).
+pub struct OtlpTracing {
+    provider: SdkTracerProvider,
+    tracer: Tracer,
+}
+
+impl OtlpTracing {
+    /// Set up the exporter and register it as the global tracer provider.
+    ///
+    /// "endpoint" is the full OTLP traces URL, i.e. "http://localhost:4318/v1/traces"
+    pub fn init(service_name: &'static str, endpoint: &str) -> Result<Self, ExporterBuildError> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(service_name).build())
+            .build();
+        let tracer = provider.tracer(service_name);
+        global::set_tracer_provider(provider.clone());
+        Ok(Self { provider, tracer })
+    }
+
+    /// Flush any remaining spans, call this before the application exits.
+    pub fn shutdown(&self) {
+        if let Err(e) = self.provider.shutdown() {
+            tracing::warn!(error = ?e, "could not flush remaining spans to the OTLP collector");
+        }
+    }
+}
+
+/// Lets the log level be changed after the subscriber has been installed.
+#[derive(Clone)]
+pub struct LogLevelHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogLevelHandle {
+    /// Replace the current filter. Note that this takes priority over the RUST_LOG environment variable.
+    pub fn set_level(&self, level: &LogLevel) -> Result<(), reload::Error> {
+        self.handle.reload(EnvFilter::new(level.to_string()))?;
+        tracing::warn!("log level changed to {}", level);
+        Ok(())
+    }
+
+    /// the current filter, as a string of directives
+    pub fn current(&self) -> Option<String> {
+        self.handle.with_current(|filter| filter.to_string()).ok()
+    }
+}
+
+/// The initial filter respects the RUST_LOG environment variable, and can be swapped out later through the returned handle
+fn reloadable_filter(
+    env_filter_arg: String,
+) -> (reload::Layer<EnvFilter, Registry>, LogLevelHandle) {
+    let env_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter_arg));
+    let (layer, handle) = reload::Layer::new(env_filter);
+    (layer, LogLevelHandle { handle })
+}
+
+/// The OpenTelemetry layer is optional, and only gets added to the subscriber if the exporter is configured.
+fn otlp_layer<S>(otlp: Option<&OtlpTracing>) -> Option<OpenTelemetryLayer<S, Tracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    otlp.map(|otlp| tracing_opentelemetry::layer().with_tracer(otlp.tracer.clone()))
+}
 
 /// Compose multiple layers into a `tracing`'s subscriber.
 ///
 /// This one uses a "pretty" format which is easier for developers to directly read in a terminal.
-pub fn get_pretty_subscriber(env_filter_arg: String) -> impl Subscriber + Send + Sync {
-    let env_filter =
-        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter_arg));
-    Registry::default()
+pub fn get_pretty_subscriber(
+    env_filter_arg: String,
+    otlp: Option<&OtlpTracing>,
+) -> (impl Subscriber + Send + Sync, LogLevelHandle) {
+    let (env_filter, handle) = reloadable_filter(env_filter_arg);
+    let subscriber = Registry::default()
         .with(env_filter)
-        .with(tracing_subscriber::fmt::layer().pretty())
+        .with(otlp_layer(otlp))
+        .with(tracing_subscriber::fmt::layer().pretty());
+    (subscriber, handle)
 }
 
 /// Compose multiple layers into a `tracing`'s subscriber.
@@ -23,16 +111,18 @@ pub fn get_json_subscriber<Sink>(
     name: String,
     env_filter_arg: String,
     sink: Sink,
-) -> impl Subscriber + Send + Sync
+    otlp: Option<&OtlpTracing>,
+) -> (impl Subscriber + Send + Sync, LogLevelHandle)
 where
     Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
 {
-    let env_filter =
-        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter_arg));
-    Registry::default()
+    let (env_filter, handle) = reloadable_filter(env_filter_arg);
+    let subscriber = Registry::default()
         .with(env_filter)
+        .with(otlp_layer(otlp))
         .with(JsonStorageLayer)
-        .with(BunyanFormattingLayer::new(name, sink))
+        .with(BunyanFormattingLayer::new(name, sink));
+    (subscriber, handle)
 }
 
 /// Register a subscriber as global default to process span data.
@@ -41,6 +131,28 @@ where
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger");
     set_global_default(subscriber).expect("Failed to set subscriber");
+    // W3C trace context ("traceparent" / "tracestate") is what INTERSECT services are expected to understand
+    global::set_text_map_propagator(TraceContextPropagator::new());
+}
+
+/// Serialize the span's trace context so it can travel alongside a message.
+///
+/// Returns an empty map if the span is not being exported.
+pub fn inject_span_context(span: &Span) -> HashMap<String, String> {
+    let mut carrier = HashMap::new();
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut carrier));
+    carrier
+}
+
+/// Continue a trace which was started elsewhere. If the carrier does not have a trace context, the span is left as a new root span.
+pub fn set_span_parent(span: &Span, carrier: &HashMap<String, String>) {
+    if carrier.is_empty() {
+        return;
+    }
+    let context = global::get_text_map_propagator(|propagator| propagator.extract(carrier));
+    // this only fails if we are not exporting spans, in which case there's nothing to continue
+    let _ = span.set_parent(context);
 }
 
 /// Use this function to maintain tracing even when calling blocking functions
@@ -52,3 +164,25 @@ where
     let current_span = tracing::Span::current();
     tokio::task::spawn_blocking(move || current_span.in_scope(f))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_level_can_be_changed_after_init() {
+        let (subscriber, handle) = get_pretty_subscriber("info".into(), None);
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(tracing::enabled!(tracing::Level::INFO));
+            assert!(!tracing::enabled!(tracing::Level::DEBUG));
+
+            handle.set_level(&LogLevel::Debug).unwrap();
+            assert!(tracing::enabled!(tracing::Level::DEBUG));
+
+            handle.set_level(&LogLevel::Warning).unwrap();
+            assert!(tracing::enabled!(tracing::Level::WARN));
+            assert!(!tracing::enabled!(tracing::Level::INFO));
+            assert_eq!(handle.current().as_deref(), Some("warn"));
+        });
+    }
+}